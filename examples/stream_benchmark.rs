@@ -0,0 +1,65 @@
+//! Compares the time and the peak memory of the regular and the streaming assembly on a large generated
+//! program, run with `cargo run --release --example stream_benchmark`
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::env;
+use std::fs;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use vilmos_assembler::assembler;
+use vilmos_assembler::assembler::Summary;
+use vilmos_assembler::color::Color;
+use vilmos_assembler::params::Params;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+/// Allocator keeping the peak of the allocated memory
+struct Counting;
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let allocated = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(allocated, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+fn main() {
+    let input = env::temp_dir().join(format!("vilmos_benchmark_{}.vasm", std::process::id()));
+    let source: String = (0..300_000).map(|i| format!("RAW_INT {}\nDUP\nSUM\nOUTPUT_INT\n", i % 2000)).collect();
+    fs::write(&input, source).unwrap();
+    let mut params = Params { input_path: input.to_string_lossy().to_string(), seed: Some(1), max_width: 1000, ..Default::default() };
+    params.read_config().unwrap();
+    let mut results: Vec<(Vec<Color>, Summary)> = Vec::new();
+    for (streaming, name) in [(false, "regular"), (true, "streaming")] {
+        params.streaming = streaming;
+        params.output_path = env::temp_dir().join(format!("vilmos_benchmark_{}_{}.png", name, std::process::id()))
+            .to_string_lossy().to_string();
+        let base = ALLOCATED.load(Ordering::Relaxed);
+        PEAK.store(base, Ordering::Relaxed);
+        let start = Instant::now();
+        let summary = assembler::assemble(&params).unwrap();
+        let elapsed = start.elapsed();
+        let peak = PEAK.load(Ordering::Relaxed) - base;
+        println!("{:<9}: {} pixels in {:.2?}, peak memory {} KiB", name, summary.pixels, elapsed, peak / 1024);
+        let pixels = assembler::read_image(&Params { input_path: params.output_path.clone(), ..Default::default() }).unwrap();
+        results.push((pixels, summary));
+        fs::remove_file(&params.output_path).unwrap();
+    }
+    fs::remove_file(input).unwrap();
+    assert_eq!(results[0].0, results[1].0);
+    assert_eq!(results[0].1.pixels, results[1].1.pixels);
+}
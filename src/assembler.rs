@@ -1,65 +1,353 @@
 use std::cmp::min;
+use std::fs;
 use std::fs::File;
-use std::io::{BufRead, BufReader, BufWriter, Write};
-use std::path::Path;
+use std::io::{BufRead, BufReader};
+use std::iter;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 
 use crate::color;
 use crate::color::Color;
+use crate::diagnostics;
+use crate::brainfuck;
+use crate::error::{Error, Location};
+use crate::frontend;
+use crate::html;
+use crate::image;
+use crate::image::PngSummary;
+use crate::preview;
 use crate::instructions::Instruction;
 use crate::params::Params;
+use crate::stats::Stats;
+use crate::stream;
+use crate::svg;
 
 const MAX_IMAGE_WIDTH: u32 = 1_000_000u32;
 
-pub fn parse(conf: &Params) -> Vec<Color> {
-    let path = &conf.input_path;
-    let mut reader = BufReader::new(File::open(path).unwrap());
+/// Finds an included file next to the file that includes it or in one of the include directories
+fn resolve_include(conf: &Params, from: &Path, name: &str) -> Result<PathBuf, String> {
+    let base = from.parent().unwrap_or_else(|| Path::new(""));
+    let candidates = iter::once(base.to_path_buf()).chain(conf.include_dirs.iter().map(PathBuf::from));
+    for dir in candidates {
+        let path = dir.join(name);
+        if path.is_file() {
+            return Ok(path);
+        }
+    }
+    Err(format!("Unable to find the included file {}", name))
+}
+
+/// Path of a data file read by an included file, resolved from the directory of that file like INCLUDE
+fn included_data_file(included: &Path, name: String) -> String {
+    if Path::new(&name).is_absolute() {
+        return name;
+    }
+    let dir = included.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+    let dir = dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf());
+    dir.join(name).to_string_lossy().to_string()
+}
+
+/// Reads the instructions of a VASM file, replacing every INCLUDE with the instructions of the included file.
+/// `on_instruction` receives the file and the location of each instruction, `stack` contains the files being read
+fn read_file(conf: &Params, path: &Path, stack: &mut Vec<PathBuf>,
+             on_instruction: &mut dyn FnMut(&Path, &Location, Instruction) -> Result<(), Error>) -> Result<(), Error> {
+    let canonical = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    if stack.contains(&canonical) {
+        return Err(Error::parse("include-cycle", format!("{} includes itself", path.display())));
+    }
+    stack.push(canonical);
+    let mut reader = BufReader::new(File::open(path).map_err(|err| Error::io(path.display(), err))?);
     let mut line = String::new();
-    let mut colors: Vec<Color> = Vec::new();
-    while reader.read_line(&mut line).unwrap() > 0 {
-        line= line.trim().parse().unwrap();
-        let y = Instruction::from_command(&line).expect("Invalid input file ");
+    let mut line_number = 0;
+    let mut offset = 0;
+    loop {
+        let read = reader.read_line(&mut line).map_err(|err| Error::io(path.display(), err))?;
+        if read == 0 {
+            break;
+        }
+        line_number += 1;
+        let location = Location::in_line(path.display(), line_number, offset, line.trim_end_matches(['\n', '\r']));
+        offset += read;
+        let y = Instruction::from_command(line.trim()).map_err(|err| Error::instruction(err, location.clone()))?;
+        let included = stack.len() > 1;
         match y {
+            Some(Instruction::RawDataFile(name)) if included => {
+                on_instruction(path, &location, Instruction::RawDataFile(included_data_file(path, name)))?
+            }
+            Some(Instruction::RawBytesFile(name)) if included => {
+                on_instruction(path, &location, Instruction::RawBytesFile(included_data_file(path, name)))?
+            }
             None => {}
-            Some(val) => { colors.append(&mut conf.get_color(val)); }
+            Some(Instruction::Include(name)) => {
+                let included = resolve_include(conf, path, &name).map_err(|err| Error::parse_at("missing-include", err, location))?;
+                read_file(conf, &included, stack, on_instruction)?;
+            }
+            Some(val) => on_instruction(path, &location, val)?
         }
         line.clear();
     }
-    colors
+    stack.pop();
+    Ok(())
 }
 
-fn fill_row(start_index: u32, count: u32, conf: &Params, colors: &Vec<Color>, buffer: &mut Vec<u8>) {
-    for i in start_index..start_index + count {
-        let color = match colors.get(i as usize) {
-            None => conf.get_color(Instruction::Quit)[0],
-            Some(c) => c.clone()
+/// Pixels of a program and what was recorded while assembling it
+pub struct Program {
+    pub colors: Vec<Color>,
+    /// Statistics, collected with the `stats` setting
+    pub stats: Option<Stats>,
+    /// Where every instruction was written, collected with the `html` setting
+    pub origins: Vec<Origin>,
+    /// VASM translation of a program that wasn't written in VASM, the origins refer to its lines
+    pub vasm: Option<String>,
+}
+
+/// Instruction written at `location` that became the pixels in `pixels`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Origin {
+    pub location: Location,
+    pub pixels: Range<usize>,
+}
+
+impl Program {
+    fn new(conf: &Params) -> Self {
+        color::seed(conf.seed.unwrap_or_else(rand::random));
+        Program { colors: Vec::new(), stats: conf.stats.then(Stats::default), origins: Vec::new(), vasm: None }
+    }
+
+    /// Appends the pixels of the instruction written at `location`
+    fn push(&mut self, conf: &Params, location: &Location, instruction: Instruction) -> Result<(), Error> {
+        let start = self.colors.len();
+        match &mut self.stats {
+            Some(stats) => stats.push(conf, instruction, &mut self.colors),
+            None => conf.get_color(instruction).map(|mut colors| self.colors.append(&mut colors))
+        }
+        .map_err(|err| Error::parse_at("invalid-argument", err, location.clone()))?;
+        if !conf.html.is_empty() {
+            self.origins.push(Origin { location: location.clone(), pixels: start..self.colors.len() });
+        }
+        Ok(())
+    }
+
+    /// Program made of instructions that weren't read from a VASM file, the origins refer to their VASM translation
+    pub fn from_instructions(conf: &Params, instructions: Vec<Instruction>) -> Result<Self, Error> {
+        let mut program = Program::new(conf);
+        let mut vasm = String::new();
+        for (i, instruction) in instructions.into_iter().enumerate() {
+            let command = instruction.to_command();
+            let location = Location::in_line(&conf.input_path, i + 1, vasm.len(), &command);
+            vasm.push_str(&command);
+            vasm.push('\n');
+            program.push(conf, &location, instruction)?;
+        }
+        program.vasm = (!conf.html.is_empty()).then_some(vasm);
+        Ok(program)
+    }
+}
+
+/// Reads the instructions of the VASM input, checking their arguments
+pub fn read_instructions(conf: &Params, on_instruction: &mut dyn FnMut(&Location, Instruction) -> Result<(), Error>) -> Result<(), Error> {
+    read_file(conf, Path::new(&conf.input_path), &mut Vec::new(), &mut |_, location, val| {
+        if let Err(err) = val.check(conf) {
+            return Err(Error::parse_at("invalid-argument", err, location.clone()));
+        }
+        on_instruction(location, val)
+    })
+}
+
+pub fn parse(conf: &Params) -> Result<Program, Error> {
+    let mut program = Program::new(conf);
+    read_instructions(conf, &mut |location, val| program.push(conf, location, val))?;
+    Ok(program)
+}
+
+/// Files read to assemble the input: the input itself, the included files and the data files
+pub fn dependencies(conf: &Params) -> Result<Vec<PathBuf>, Error> {
+    let input = PathBuf::from(&conf.input_path);
+    if conf.structured {
+        return Ok(vec![input]);
+    }
+    let base = input.parent().unwrap_or_else(|| Path::new("")).to_path_buf();
+    let mut files: Vec<PathBuf> = vec![input.clone()];
+    read_file(conf, &input, &mut Vec::new(), &mut |path, _, val| {
+        let file = match val {
+            Instruction::RawDataFile(name) | Instruction::RawBytesFile(name) => base.join(name),
+            _ => path.to_path_buf()
         };
-        for _ in 0..conf.pixel_size {
-            color.write_data(buffer);
+        if !files.contains(&file) {
+            files.push(file);
         }
+        Ok(())
+    })?;
+    Ok(files)
+}
+
+/// Source of the input, a VASM input that includes other files is written with their instructions in place of the INCLUDEs
+pub fn source(conf: &Params) -> Result<String, Error> {
+    let source = read_input(conf)?;
+    if conf.language() != "vasm" {
+        return Ok(source);
     }
+    let input = Path::new(&conf.input_path);
+    let mut includes = false;
+    let mut expanded = String::new();
+    read_file(conf, input, &mut Vec::new(), &mut |path, _, val| {
+        includes |= path != input;
+        expanded.push_str(&val.to_command());
+        expanded.push('\n');
+        Ok(())
+    })?;
+    Ok(if includes { expanded } else { source })
 }
 
+fn read_input(conf: &Params) -> Result<String, Error> {
+    fs::read_to_string(&conf.input_path).map_err(|err| Error::io(&conf.input_path, err))
+}
 
-pub fn write_image(conf: &Params, colors: &Vec<Color>) {
-    let size = colors.len() as u32;
-    let pixel_size = conf.pixel_size as u32;
-    let pixel_per_row = if conf.max_width == -1 { MAX_IMAGE_WIDTH / pixel_size } else { conf.max_width as u32 };
-    let pixel_per_row = min(pixel_per_row, size);
-    let height = size / pixel_per_row + u32::from(size % pixel_per_row != 0);
-    let path = Path::new(&conf.output_path);
-    let file = File::create(path).expect("Unable to create output file");
-    let ref mut w = BufWriter::new(file);
-    let mut encoder = png::Encoder::new(w, pixel_per_row * pixel_size, height * pixel_size);
-    encoder.set_color(png::ColorType::Rgb);
-    encoder.set_depth(png::BitDepth::Eight);
-    let mut writer = encoder.write_header().expect("Unable to write file");
-    let mut stream = writer.stream_writer().expect("Unable to write file");
-    let mut buffer: Vec<u8> = Vec::with_capacity((pixel_size * pixel_per_row * color::COLOR_COMPONENTS as u32) as usize);
-    for i in 0..height {
-        fill_row(i * pixel_per_row, pixel_per_row, conf, colors, &mut buffer);
-        for _ in 0..pixel_size {
-            stream.write(&buffer).expect("Error while writing the output file");
+/// Compiles the input file written in the structured language
+pub fn compile_structured(conf: &Params) -> Result<Vec<Instruction>, Error> {
+    let source = read_input(conf)?;
+    let instructions = frontend::compile(&source)
+        .map_err(|(line, err)| Error::parse_at("structured-syntax", err, Location::of_line(&conf.input_path, &source, line)))?;
+    for instruction in &instructions {
+        instruction.check(conf)
+            .map_err(|err| Error::parse("invalid-argument", format!("Invalid input file {}: {}", conf.input_path, err)))?;
+    }
+    Ok(instructions)
+}
+
+/// Translates the input file written in Brainfuck
+pub fn translate_brainfuck(conf: &Params) -> Result<Vec<Instruction>, Error> {
+    brainfuck::translate(&read_input(conf)?, conf.tape_size, conf.wrap)
+        .map_err(|err| Error::parse("brainfuck-syntax", format!("Invalid input file: {}", err)))
+}
+
+pub fn instructions_to_colors(conf: &Params, instructions: Vec<Instruction>) -> Result<Vec<Color>, Error> {
+    let colors = instructions.into_iter().map(|instruction| conf.get_color(instruction)).collect::<Result<Vec<_>, _>>();
+    Ok(colors.map_err(|err| Error::parse("invalid-argument", err))?.concat())
+}
+
+pub fn write_vasm(conf: &Params, instructions: &[Instruction]) -> Result<(), Error> {
+    let mut text = String::new();
+    for instruction in instructions {
+        text.push_str(&instruction.to_command());
+        text.push('\n');
+    }
+    fs::write(&conf.output_path, text).map_err(|err| Error::io(&conf.output_path, err))
+}
+
+/// Pixels of the program, from the VASM or structured input
+pub fn colors(conf: &Params) -> Result<Program, Error> {
+    if conf.structured {
+        Program::from_instructions(conf, compile_structured(conf)?)
+    } else {
+        parse(conf)
+    }
+}
+
+/// Assembles the input file into the image, the structured language is written as VASM with `emit_vasm`
+pub fn assemble(conf: &Params) -> Result<Summary, Error> {
+    if conf.structured && conf.emit_vasm {
+        let instructions = compile_structured(conf)?;
+        write_vasm(conf, &instructions)?;
+        return Ok(Summary::new(conf, instructions_to_colors(conf, instructions)?.len()));
+    }
+    if conf.streaming {
+        return stream::assemble(conf);
+    }
+    write_program(conf, colors(conf)?)
+}
+
+/// Writes the image of the program and, with the `html` setting, its visualizer
+pub fn write_program(conf: &Params, program: Program) -> Result<Summary, Error> {
+    let summary = write_output(conf, &program.colors)?;
+    if !conf.html.is_empty() {
+        html::write_html(conf, &program)?;
+    }
+    if conf.preview {
+        diagnostics::note(preview::render(conf, &program.colors));
+    }
+    Ok(Summary { stats: program.stats, ..summary })
+}
+
+/// Writes the pixels as an SVG when the output ends with `.svg`, as a PNG otherwise
+pub fn write_output(conf: &Params, colors: &[Color]) -> Result<Summary, Error> {
+    if conf.output_path.to_lowercase().ends_with(".svg") {
+        svg::write_svg(conf, colors)
+    } else {
+        write_image(conf, colors)
+    }
+}
+
+/// Reads the pixels of an image, one every `pixel_size` in both directions
+pub fn read_image(conf: &Params) -> Result<Vec<Color>, Error> {
+    let io_error = |err: png::DecodingError| Error::io(&conf.input_path, err);
+    let file = File::open(&conf.input_path).map_err(|err| Error::io(&conf.input_path, err))?;
+    let mut decoder = png::Decoder::new(file);
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info().map_err(io_error)?;
+    let mut data = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut data).map_err(io_error)?;
+    let components = info.color_type.samples();
+    let pixel_size = conf.pixel_size.max(1) as usize;
+    let mut colors: Vec<Color> = Vec::new();
+    for y in (0..info.height as usize).step_by(pixel_size) {
+        let row = &data[y * info.line_size..(y + 1) * info.line_size];
+        for x in (0..info.width as usize).step_by(pixel_size) {
+            let pixel = &row[x * components..(x + 1) * components];
+            colors.push(match info.color_type {
+                png::ColorType::Grayscale | png::ColorType::GrayscaleAlpha => Color::new(pixel[0], pixel[0], pixel[0]),
+                _ => Color::new(pixel[0], pixel[1], pixel[2])
+            });
         }
-        buffer.clear();
+    }
+    Ok(colors)
+}
+
+/// Number of pixels of a program and size of its image
+#[derive(Clone, Debug, PartialEq)]
+pub struct Summary {
+    pub pixels: usize,
+    pub width: u32,
+    pub height: u32,
+    /// `QUIT` pixels filling the last row
+    pub padding: usize,
+    /// Statistics collected with the `stats` setting
+    pub stats: Option<Stats>,
+    /// Format and size of PNG outputs
+    pub png: Option<PngSummary>,
+}
+
+impl Summary {
+    pub fn new(conf: &Params, pixels: usize) -> Self {
+        let (pixel_per_row, height) = layout(conf, pixels);
+        let pixel_size = conf.pixel_size as u32;
+        let padding = (pixel_per_row * height) as usize - pixels;
+        Summary { pixels, width: pixel_per_row * pixel_size, height: height * pixel_size, padding, stats: None, png: None }
+    }
+}
+
+/// Pixels per row and rows of the image of a program with `pixels` pixels
+pub fn layout(conf: &Params, pixels: usize) -> (u32, u32) {
+    let size = (pixels as u32).max(1);
+    let pixel_per_row = if conf.max_width == -1 { MAX_IMAGE_WIDTH / conf.pixel_size as u32 } else { conf.max_width as u32 };
+    let pixel_per_row = min(pixel_per_row, size);
+    (pixel_per_row, size / pixel_per_row + u32::from(!size.is_multiple_of(pixel_per_row)))
+}
+
+pub fn write_image(conf: &Params, colors: &[Color]) -> Result<Summary, Error> {
+    let png = image::write_png(conf, colors)?;
+    Ok(Summary { png: Some(png), ..Summary::new(conf, colors.len()) })
+}
+
+#[cfg(test)]
+mod assembler_tests {
+    use super::*;
+
+    #[test]
+    fn included_data_files() {
+        let params = Params { input_path: "tests/data/main.vasm".to_string(), ..Params::test() };
+        let table = Path::new("tests/data/lib/table.txt").canonicalize().unwrap();
+        assert_eq!(Some(&table), dependencies(&params).unwrap().last());
+        assert_eq!(vec![Color::not_random(7), Color::not_random(8)], parse(&params).unwrap().colors);
     }
 }
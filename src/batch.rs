@@ -0,0 +1,221 @@
+//! Assembly of many inputs in one run.
+
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use crate::assembler;
+use crate::assembler::Summary;
+use crate::diagnostics;
+use crate::error::Error;
+use crate::params::Params;
+
+/// Checks if `name` matches a pattern with `*` and `?`
+fn matches(pattern: &[char], name: &[char]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some('*') => (0..=name.len()).any(|skip| matches(&pattern[1..], &name[skip..])),
+        Some('?') => !name.is_empty() && matches(&pattern[1..], &name[1..]),
+        Some(c) => name.first() == Some(c) && matches(&pattern[1..], &name[1..])
+    }
+}
+
+fn is_pattern(str: &str) -> bool {
+    str.contains(['*', '?'])
+}
+
+/// Directories below `dir`, `dir` included
+fn subdirectories(dir: &Path, found: &mut Vec<PathBuf>) {
+    found.push(dir.to_path_buf());
+    let entries = match fs::read_dir(if dir.as_os_str().is_empty() { Path::new(".") } else { dir }) {
+        Ok(entries) => entries,
+        Err(_) => return
+    };
+    let mut dirs: Vec<PathBuf> = entries.flatten()
+        .filter(|entry| entry.path().is_dir() && !entry.file_name().to_string_lossy().starts_with('.'))
+        .map(|entry| dir.join(entry.file_name()))
+        .collect();
+    dirs.sort();
+    for sub in dirs {
+        subdirectories(&sub, found);
+    }
+}
+
+/// Files matching `pattern`, sorted by path. A path without wildcards is returned as is
+pub fn expand(pattern: &str) -> Result<Vec<PathBuf>, Error> {
+    if !is_pattern(pattern) {
+        return Ok(vec![PathBuf::from(pattern)]);
+    }
+    let mut paths: Vec<PathBuf> = vec![PathBuf::new()];
+    for component in Path::new(pattern).components() {
+        let name = match component {
+            Component::Normal(name) => name.to_string_lossy().to_string(),
+            _ => {
+                paths.iter_mut().for_each(|path| path.push(component.as_os_str()));
+                continue;
+            }
+        };
+        let mut next: Vec<PathBuf> = Vec::new();
+        for path in &paths {
+            if name == "**" {
+                subdirectories(path, &mut next);
+            } else if !is_pattern(&name) {
+                next.push(path.join(&name));
+            } else if let Ok(entries) = fs::read_dir(if path.as_os_str().is_empty() { Path::new(".") } else { path }) {
+                let pattern: Vec<char> = name.chars().collect();
+                for entry in entries.flatten() {
+                    let file_name = entry.file_name().to_string_lossy().to_string();
+                    let hidden = file_name.starts_with('.') && !name.starts_with('.');
+                    if !hidden && matches(&pattern, &file_name.chars().collect::<Vec<char>>()) {
+                        next.push(path.join(file_name));
+                    }
+                }
+            }
+        }
+        paths = next;
+    }
+    paths.retain(|path| path.is_file());
+    paths.sort();
+    paths.dedup();
+    if paths.is_empty() {
+        return Err(Error::io(pattern, "No file matches the pattern"));
+    }
+    Ok(paths)
+}
+
+/// Expands every input pattern, the path is also returned alone when there is exactly one
+pub fn expand_inputs(inputs: &[String]) -> Result<(Vec<PathBuf>, Option<String>), Error> {
+    let mut paths: Vec<PathBuf> = Vec::new();
+    for input in inputs {
+        paths.extend(expand(input)?);
+    }
+    let single = match paths.as_slice() {
+        [path] => Some(path.to_string_lossy().to_string()),
+        _ => None
+    };
+    Ok((paths, single))
+}
+
+/// Output path of `input` for the output pattern
+pub fn output_path(pattern: &str, input: &Path) -> String {
+    let stem = input.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default();
+    let dir = input.parent().map(|dir| dir.to_string_lossy().to_string()).unwrap_or_default();
+    let dir = if dir.is_empty() { ".".to_string() } else { dir };
+    pattern.replace("{stem}", &stem).replace("{dir}", &dir)
+}
+
+/// Paths of the inputs for the pattern of the `setting`, refused when two inputs would write the same file
+fn unique_paths(pattern: &str, inputs: &[PathBuf], setting: &str) -> Result<Vec<String>, Error> {
+    let paths: Vec<String> = inputs.iter().map(|input| output_path(pattern, input)).collect();
+    if paths.iter().enumerate().any(|(i, path)| paths[..i].contains(path)) {
+        return Err(Error::Usage(format!("Many inputs are written to {}, use {{stem}} in the {}", pattern, setting)));
+    }
+    Ok(paths)
+}
+
+/// Assembles every input with the settings of `conf`, printing the failures and a summary.
+/// The error of the first failed input is returned
+pub fn assemble_all(conf: &Params, inputs: &[PathBuf], output_pattern: &str) -> Result<(), Error> {
+    let outputs = unique_paths(output_pattern, inputs, "output")?;
+    let html = match conf.html.is_empty() {
+        true => vec![String::new(); inputs.len()],
+        false => unique_paths(&conf.html, inputs, "html page")?
+    };
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<Result<Summary, Error>>>> = Mutex::new(vec![None; inputs.len()]);
+    let workers = thread::available_parallelism().map_or(1, |count| count.get()).min(inputs.len());
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                if i >= inputs.len() {
+                    break;
+                }
+                let mut params = conf.clone();
+                params.input_path = inputs[i].to_string_lossy().to_string();
+                params.output_path = outputs[i].clone();
+                params.html = html[i].clone();
+                let result = match Path::new(&params.output_path).parent() {
+                    Some(dir) if !dir.as_os_str().is_empty() => fs::create_dir_all(dir).map_err(|err| Error::io(dir.display(), err)),
+                    _ => Ok(())
+                }.and_then(|_| assembler::assemble(&params));
+                results.lock().unwrap()[i] = Some(result);
+            });
+        }
+    });
+    let results = results.into_inner().unwrap();
+    let mut first_error: Option<Error> = None;
+    let mut failed = 0;
+    for (i, result) in results.into_iter().enumerate() {
+        match result.expect("Input not assembled") {
+            Ok(summary) => {
+                let input = inputs[i].to_string_lossy();
+                diagnostics::summary(&input, &outputs[i], &summary, Some(format!("{} -> {}", inputs[i].display(), outputs[i])));
+            }
+            Err(err) => {
+                diagnostics::error(&err);
+                failed += 1;
+                first_error.get_or_insert(err);
+            }
+        }
+    }
+    diagnostics::note(format!("{} assembled, {} failed", inputs.len() - failed, failed));
+    match first_error {
+        None => Ok(()),
+        Some(err) => Err(err.with_message(format!("{} of {} inputs failed", failed, inputs.len())))
+    }
+}
+
+#[cfg(test)]
+mod batch_tests {
+    use super::*;
+
+    fn matches_str(pattern: &str, name: &str) -> bool {
+        matches(&pattern.chars().collect::<Vec<char>>(), &name.chars().collect::<Vec<char>>())
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(matches_str("*.vasm", "hello.vasm"));
+        assert!(matches_str("h?llo*", "hello.vasm"));
+        assert!(matches_str("*", ""));
+        assert!(!matches_str("*.vasm", "hello.png"));
+        assert!(!matches_str("?", ""));
+    }
+
+    #[test]
+    fn globs() {
+        assert_eq!(vec![PathBuf::from("tests/project/src/hello.vasm")], expand("tests/project/src/*.vasm").unwrap());
+        assert_eq!(vec![PathBuf::from("tests/project/lib/newline.vasm"), PathBuf::from("tests/project/src/hello.vasm")],
+                   expand("tests/project/**/*.vasm").unwrap());
+        assert_eq!(vec![PathBuf::from("missing.vasm")], expand("missing.vasm").unwrap());
+        assert!(expand("tests/*.missing").is_err());
+    }
+
+    #[test]
+    fn single_match() {
+        let (paths, single) = expand_inputs(&["tests/project/src/*.vasm".to_string()]).unwrap();
+        assert_eq!(vec![PathBuf::from("tests/project/src/hello.vasm")], paths);
+        assert_eq!(Some("tests/project/src/hello.vasm".to_string()), single);
+        let (paths, single) = expand_inputs(&["tests/project/**/*.vasm".to_string()]).unwrap();
+        assert_eq!((2, None), (paths.len(), single));
+    }
+
+    #[test]
+    fn outputs() {
+        assert_eq!("out/hello.png", output_path("out/{stem}.png", Path::new("src/hello.vasm")));
+        assert_eq!("src/hello.png", output_path("{dir}/{stem}.png", Path::new("src/hello.vasm")));
+        assert_eq!("./hello.png", output_path("{dir}/{stem}.png", Path::new("hello.vasm")));
+    }
+
+    #[test]
+    fn shared_outputs() {
+        let inputs = expand("tests/project/**/*.vasm").unwrap();
+        let conf = Params { html: "out/{stem}.html".to_string(), ..Default::default() };
+        assert!(matches!(assemble_all(&conf, &inputs, "out.png"), Err(Error::Usage(_))));
+        let conf = Params { html: "out.html".to_string(), ..Default::default() };
+        assert!(matches!(assemble_all(&conf, &inputs, "out/{stem}.png"), Err(Error::Usage(_))));
+    }
+}
@@ -0,0 +1,131 @@
+//! Translation of Brainfuck programs into VASM instructions.
+
+use crate::instructions::Instruction;
+
+pub const DEFAULT_TAPE_SIZE: u32 = 1000;
+const CELL_VALUES: i32 = 256;
+
+/// Pushes `size` zeros with a loop, the counter stays at the bottom of the stack while the zeros are pushed
+fn tape(size: u32) -> Vec<Instruction> {
+    vec![
+        Instruction::RawInt(size as i32),
+        Instruction::While,
+        Instruction::RawInt(1),
+        Instruction::Sub,
+        Instruction::Cycle,
+        Instruction::RawInt(0),
+        Instruction::Rcycle,
+        Instruction::WhileEnd,
+        Instruction::Pop,
+    ]
+}
+
+/// Adds `amount` to the current cell, keeping it between 0 and 255 when `wrap` is set
+fn add(amount: i32, wrap: bool) -> Vec<Instruction> {
+    if !wrap {
+        return match amount {
+            0 => vec![],
+            _ if amount > 0 => vec![Instruction::RawInt(amount), Instruction::Sum],
+            _ => vec![Instruction::RawInt(-amount), Instruction::Sub]
+        };
+    }
+    let amount = amount.rem_euclid(CELL_VALUES);
+    if amount == 0 {
+        return vec![];
+    }
+    vec![Instruction::RawInt(amount), Instruction::Sum, Instruction::RawInt(CELL_VALUES), Instruction::Mod]
+}
+
+/// Translates a Brainfuck program, every character that isn't a command is a comment
+pub fn translate(source: &str, tape_size: u32, wrap: bool) -> Result<Vec<Instruction>, String> {
+    if tape_size == 0 {
+        return Err("The tape must have at least one cell".to_string());
+    }
+    let commands: Vec<char> = source.chars().filter(|c| "+-<>[].,".contains(*c)).collect();
+    let mut instructions = tape(tape_size);
+    let mut open_loops = 0;
+    let mut i = 0;
+    while i < commands.len() {
+        let command = commands[i];
+        let mut run = 1;
+        while "+-<>".contains(command) && i + run < commands.len() && commands[i + run] == command {
+            run += 1;
+        }
+        i += run;
+        match command {
+            '+' => instructions.extend(add(run as i32, wrap)),
+            '-' => instructions.extend(add(-(run as i32), wrap)),
+            '>' => instructions.extend(vec![Instruction::Rcycle; run % tape_size as usize]),
+            '<' => instructions.extend(vec![Instruction::Cycle; run % tape_size as usize]),
+            '.' => instructions.extend(vec![Instruction::Dup, Instruction::OutputAscii]),
+            ',' => instructions.extend(vec![Instruction::Pop, Instruction::InputAscii]),
+            '[' => {
+                open_loops += 1;
+                instructions.push(Instruction::While);
+            }
+            _ => {
+                if open_loops == 0 {
+                    return Err("Unmatched ]".to_string());
+                }
+                open_loops -= 1;
+                instructions.push(Instruction::WhileEnd);
+            }
+        }
+    }
+    if open_loops != 0 {
+        return Err("Unmatched [".to_string());
+    }
+    Ok(instructions)
+}
+
+#[cfg(test)]
+mod brainfuck_tests {
+    use crate::color::Color;
+    use crate::interpreter::Interpreter;
+    use crate::params::Params;
+
+    use super::*;
+
+    fn run(source: &str, input: &str, tape_size: u32, wrap: bool) -> String {
+        let params = Params::test();
+        let instructions = translate(source, tape_size, wrap).unwrap();
+        let colors: Vec<Color> = instructions.into_iter().flat_map(|instruction| params.get_color(instruction).unwrap()).collect();
+        let mut input = input.as_bytes();
+        let mut output: Vec<u8> = Vec::new();
+        Interpreter::new(&colors, &params, &mut input, &mut output).unwrap().run().unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn hello_world() {
+        let source = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+        assert_eq!("Hello World!\n", run(source, "", DEFAULT_TAPE_SIZE, true));
+    }
+
+    #[test]
+    fn cat() {
+        assert_eq!("Vilmos", run(",[.,]", "Vilmos", 10, true));
+    }
+
+    #[test]
+    fn wrapping() {
+        assert_eq!("\u{ff}", run("-.", "", 5, true));
+        assert_eq!("A", run(&format!("-{}.", "+".repeat(66)), "", 5, true));
+        assert_eq!("A", run(&format!("-{}.", "+".repeat(66)), "", 5, false));
+        assert_eq!(vec![Instruction::RawInt(3), Instruction::Sub], add(-3, false));
+        assert_eq!(vec![Instruction::RawInt(253), Instruction::Sum, Instruction::RawInt(256), Instruction::Mod], add(-3, true));
+    }
+
+    #[test]
+    fn circular_tape() {
+        assert_eq!("A", run(&format!("{}<<<.", "+".repeat(65)), "", 3, true));
+        assert_eq!("\0B", run(&format!("<{}>.<.", "+".repeat(66)), "", 3, true));
+    }
+
+    #[test]
+    fn unbalanced() {
+        assert!(translate("[", 10, true).is_err());
+        assert!(translate("]", 10, true).is_err());
+        assert!(translate("+", 0, true).is_err());
+    }
+}
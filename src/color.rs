@@ -1,8 +1,28 @@
-use rand::{random, thread_rng};
+use std::cell::{Cell, RefCell};
+
+use rand::{random, Rng, SeedableRng};
+use rand::rngs::StdRng;
 use rand::seq::SliceRandom;
 
 pub const COLOR_COMPONENTS: usize = 3;
 
+thread_local! {
+    static SEED: Cell<u64> = Cell::new(random());
+    /// Generator of the random colors of this thread, recreated by `seed`
+    static RNG: RefCell<StdRng> = RefCell::new(StdRng::seed_from_u64(SEED.with(Cell::get)));
+}
+
+/// Restarts the generator of the random colors of this thread from `seed`
+pub fn seed(seed: u64) {
+    SEED.with(|current| current.set(seed));
+    RNG.with(|rng| *rng.borrow_mut() = StdRng::seed_from_u64(seed));
+}
+
+/// Seed of the generator of the random colors of this thread
+pub fn current_seed() -> u64 {
+    SEED.with(Cell::get)
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub struct Color {
     r: u8,
@@ -15,7 +35,7 @@ impl From<u32> for Color {
         Color {
             r: (val >> 16) as u8,
             g: (val >> 8) as u8,
-            b: (val >> 0) as u8,
+            b: val as u8,
         }
     }
 }
@@ -38,18 +58,21 @@ impl Color {
     pub fn random(value: i32) -> Self {
         let mut components = [0u8; COLOR_COMPONENTS];
         let mut value = value;
-        while value != 0 {
-            let min = *components.iter().min().unwrap() as i32;
-            let i = 1 + (random::<u8>() as i32 % value.min(255 - min)) as u8;
-            for item in 0..3 {
-                if let Some(new_val) = components[item].checked_add(i) {
-                    components[item] = new_val;
-                    break;
+        RNG.with(|rng| {
+            let rng = &mut *rng.borrow_mut();
+            while value != 0 {
+                let min = *components.iter().min().unwrap() as i32;
+                let i = 1 + (rng.gen::<u8>() as i32 % value.min(255 - min)) as u8;
+                for component in components.iter_mut() {
+                    if let Some(new_val) = component.checked_add(i) {
+                        *component = new_val;
+                        break;
+                    }
                 }
+                components.shuffle(rng);
+                value -= i as i32;
             }
-            components.shuffle(&mut thread_rng());
-            value -= i as i32;
-        }
+        });
 
         Color { r: components[0], g: components[1], b: components[2] }
     }
@@ -74,6 +97,25 @@ impl From<char> for Color {
 }
 
 impl Color {
+    /// Value pushed by a pixel that isn't an instruction
+    pub fn value(&self) -> i32 {
+        self.r as i32 + self.g as i32 + self.b as i32
+    }
+
+    pub fn components(&self) -> (u8, u8, u8) {
+        (self.r, self.g, self.b)
+    }
+
+    /// Checks if white text is more readable than black text on this color
+    pub fn is_dark(&self) -> bool {
+        299 * u32::from(self.r) + 587 * u32::from(self.g) + 114 * u32::from(self.b) < 128 * 1000
+    }
+
+    /// Lowercase `rrggbb` representation
+    pub fn hex(&self) -> String {
+        format!("{:02x}{:02x}{:02x}", self.r, self.g, self.b)
+    }
+
     pub fn write_data(&self, data: &mut Vec<u8>) {
         data.push(self.r);
         data.push(self.g);
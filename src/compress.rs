@@ -0,0 +1,132 @@
+use crate::color::Color;
+use crate::instructions::{chars_to_colors, int_to_colors, next_char_to_colors, uncounted, Instruction};
+use crate::params::Params;
+
+const MAX_PATTERN_LENGTH: usize = 64;
+
+/// Number of opcodes needed by a repetition loop besides the counter, the decrement and the pattern:
+/// `WHILE SUB CYCLE RCYCLE WHILE_END POP`
+const LOOP_OVERHEAD: usize = 6;
+
+#[derive(Clone, Copy)]
+enum Step {
+    Char,
+    Repeat { length: usize, times: usize },
+}
+
+/// Pushes `pattern` `times` times using a loop.
+/// The counter is moved to the bottom of the stack with `CYCLE` while the pattern is pushed
+/// and brought back with `RCYCLE` for [`Instruction::While`] to test it
+fn repeat_to_colors(pattern: &[char], times: usize, conf: &Params) -> Vec<Color> {
+    let mut colors: Vec<Color> = Vec::new();
+    colors.extend(int_to_colors(times as i32, conf));
+    colors.push(conf.opcode_color(Instruction::While));
+    colors.extend(int_to_colors(1, conf));
+    colors.push(conf.opcode_color(Instruction::Sub));
+    colors.push(conf.opcode_color(Instruction::Cycle));
+    colors.extend(chars_to_colors(pattern, None, conf));
+    colors.push(conf.opcode_color(Instruction::Rcycle));
+    colors.push(conf.opcode_color(Instruction::WhileEnd));
+    colors.push(conf.opcode_color(Instruction::Pop));
+    colors
+}
+
+fn repetitions(chars: &[char], start: usize, length: usize) -> usize {
+    let pattern = &chars[start..start + length];
+    let mut times = 1;
+    while start + (times + 1) * length <= chars.len()
+        && chars[start + times * length..start + (times + 1) * length] == *pattern {
+        times += 1;
+    }
+    times
+}
+
+/// Pushes `chars` like `chars_to_colors` does, replacing repeated substrings with loops
+/// whenever the loop is shorter than pushing every repetition
+pub fn compress_chars(chars: &[char], last_char: Option<char>, conf: &Params) -> Vec<Color> {
+    let n = chars.len();
+    let previous = |i: usize| if i == 0 { last_char } else { Some(chars[i - 1]) };
+    // The costs are measured on colors that aren't emitted, their fallbacks aren't counted
+    let measure = |colors: &dyn Fn() -> Vec<Color>| uncounted(colors).0.len();
+    let char_cost: Vec<usize> = (0..n).map(|i| measure(&|| next_char_to_colors(chars[i], previous(i), conf))).collect();
+    // A loop repeats at least twice, the costs of 0 and 1 are never used
+    let mut counter_cost: Vec<usize> = vec![0; 2];
+    let decrement_cost = measure(&|| int_to_colors(1, conf));
+
+    // best[i] is the cost of pushing chars[i..], the top of the stack is always chars[i - 1]
+    let mut best: Vec<usize> = vec![0; n + 1];
+    let mut steps: Vec<Step> = vec![Step::Char; n];
+    for i in (0..n).rev() {
+        best[i] = char_cost[i] + best[i + 1];
+        let mut body_cost = 0;
+        for length in 1..=MAX_PATTERN_LENGTH.min((n - i) / 2) {
+            let body_last_char = if length == 1 { None } else { Some(chars[i + length - 2]) };
+            body_cost += measure(&|| next_char_to_colors(chars[i + length - 1], body_last_char, conf));
+            let max_times = repetitions(chars, i, length);
+            for times in 2..=max_times {
+                while counter_cost.len() <= times {
+                    let times = counter_cost.len() as i32;
+                    counter_cost.push(measure(&|| int_to_colors(times, conf)));
+                }
+                let cost = counter_cost[times] + decrement_cost + LOOP_OVERHEAD + body_cost + best[i + length * times];
+                if cost < best[i] {
+                    best[i] = cost;
+                    steps[i] = Step::Repeat { length, times };
+                }
+            }
+        }
+    }
+
+    let mut colors: Vec<Color> = Vec::new();
+    let mut i = 0;
+    while i < n {
+        match steps[i] {
+            Step::Char => {
+                colors.extend(next_char_to_colors(chars[i], previous(i), conf));
+                i += 1;
+            }
+            Step::Repeat { length, times } => {
+                colors.extend(repeat_to_colors(&chars[i..i + length], times, conf));
+                i += length * times;
+            }
+        }
+    }
+    colors
+}
+
+#[cfg(test)]
+mod compress_tests {
+
+    use super::*;
+
+    fn get_params() -> Params {
+        Params { max_width: 30, compress_strings: true, ..Params::test() }
+    }
+
+    #[test]
+    fn short_string_is_not_changed() {
+        let params = get_params();
+        let chars: Vec<char> = "abcabcabc".chars().collect();
+        assert_eq!(chars_to_colors(&chars, None, &params), compress_chars(&chars, None, &params));
+    }
+
+    #[test]
+    fn repeated_pattern_uses_loop() {
+        let params = get_params();
+        let chars: Vec<char> = "xabcabcabcabcabcabcy".chars().collect();
+        let mut output = vec![Color::not_random('x' as i32)];
+        output.extend(repeat_to_colors(&chars[1..4], 6, &params));
+        output.push(Color::not_random('y' as i32));
+        assert_eq!(output, compress_chars(&chars, None, &params));
+        assert!(output.len() < chars_to_colors(&chars, None, &params).len());
+    }
+
+    #[test]
+    fn long_run_uses_loop() {
+        let params = get_params();
+        let chars: Vec<char> = "a".repeat(40).chars().collect();
+        let colors = compress_chars(&chars, Some('\0'), &params);
+        assert_eq!(repeat_to_colors(&['a'], 40, &params), colors);
+        assert!(colors.len() < chars_to_colors(&chars, Some('\0'), &params).len());
+    }
+}
@@ -0,0 +1,532 @@
+//! Loader for the configuration files, in INI, TOML or JSON format.
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::color::Color;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Str(String),
+    Int(i64),
+    Bool(bool),
+    List(Vec<Value>),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Str(str) => write!(f, "{:?}", str),
+            Value::Int(val) => write!(f, "{}", val),
+            Value::Bool(val) => write!(f, "{}", val),
+            Value::List(values) => {
+                let values: Vec<String> = values.iter().map(Value::to_string).collect();
+                write!(f, "[{}]", values.join(", "))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub section: String,
+    pub key: String,
+    pub value: Value,
+    pub line: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    Ini,
+    Toml,
+    Json,
+}
+
+impl Format {
+    /// Format of a file from its extension, INI when unknown
+    fn of(path: &str) -> Format {
+        let extension = Path::new(path).extension().map(|ext| ext.to_string_lossy().to_lowercase());
+        match extension.as_deref() {
+            Some("toml") => Format::Toml,
+            Some("json") => Format::Json,
+            _ => Format::Ini
+        }
+    }
+}
+
+fn unquote(value: &str) -> &str {
+    let quoted = value.len() >= 2
+        && ((value.starts_with('"') && value.ends_with('"')) || (value.starts_with('\'') && value.ends_with('\'')));
+    if quoted { &value[1..value.len() - 1] } else { value }
+}
+
+fn parse_ini(text: &str) -> Result<Vec<Entry>, (usize, String)> {
+    let mut entries: Vec<Entry> = Vec::new();
+    let mut section = String::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(';') || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('[') {
+            if !line.ends_with(']') {
+                return Err((i + 1, "Missing ] in section name".to_string()));
+            }
+            section = line[1..line.len() - 1].trim().to_lowercase();
+            continue;
+        }
+        let separator = line.find(['=', ':']).ok_or((i + 1, format!("Expected key=value, found {:?}", line)))?;
+        entries.push(Entry {
+            section: section.clone(),
+            key: line[..separator].trim().to_string(),
+            value: Value::Str(unquote(line[separator + 1..].trim()).to_string()),
+            line: i + 1,
+        });
+    }
+    Ok(entries)
+}
+
+/// Removes a `#` comment that isn't inside a string
+fn strip_toml_comment(line: &str) -> &str {
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match quote {
+            Some('"') if escaped => escaped = false,
+            Some('"') if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == '#' => return &line[..i],
+            None => {}
+        }
+    }
+    line
+}
+
+fn parse_toml_string(value: &str) -> Result<String, String> {
+    if value.starts_with('\'') {
+        return Ok(unquote(value).to_string());
+    }
+    let mut str = String::new();
+    let mut chars = unquote(value).chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            str.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => str.push('\n'),
+            Some('t') => str.push('\t'),
+            Some('r') => str.push('\r'),
+            Some('"') => str.push('"'),
+            Some('\\') => str.push('\\'),
+            _ => return Err(format!("Invalid escape sequence in {}", value))
+        }
+    }
+    Ok(str)
+}
+
+/// Splits the elements of a single line array at the commas outside of strings
+fn split_toml_array(value: &str) -> Vec<&str> {
+    let mut elements: Vec<&str> = Vec::new();
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    let mut start = 0;
+    for (i, c) in value.char_indices() {
+        match quote {
+            Some('"') if escaped => escaped = false,
+            Some('"') if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == ',' => {
+                elements.push(value[start..i].trim());
+                start = i + 1;
+            }
+            None => {}
+        }
+    }
+    elements.push(value[start..].trim());
+    if elements.last() == Some(&"") {
+        elements.pop();
+    }
+    elements
+}
+
+fn parse_toml_value(value: &str) -> Result<Value, String> {
+    if let Some(elements) = value.strip_prefix('[').and_then(|rest| rest.strip_suffix(']')) {
+        let mut values: Vec<Value> = Vec::new();
+        for element in split_toml_array(elements) {
+            if element.starts_with('[') {
+                return Err(format!("Nested arrays are not supported: {}", value));
+            }
+            values.push(parse_toml_value(element)?);
+        }
+        return Ok(Value::List(values));
+    }
+    let quoted = value.len() >= 2
+        && ((value.starts_with('"') && value.ends_with('"')) || (value.starts_with('\'') && value.ends_with('\'')));
+    if quoted {
+        return parse_toml_string(value).map(Value::Str);
+    }
+    match value {
+        "true" => return Ok(Value::Bool(true)),
+        "false" => return Ok(Value::Bool(false)),
+        _ => {}
+    }
+    let digits = value.replace('_', "");
+    let (negative, digits) = match digits.strip_prefix('-') {
+        Some(rest) => (true, rest.to_string()),
+        None => (false, digits.trim_start_matches('+').to_string())
+    };
+    let number = match digits.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16),
+        None => digits.parse::<i64>()
+    };
+    match number {
+        Ok(val) => Ok(Value::Int(if negative { -val } else { val })),
+        Err(_) => Err(format!("Unsupported value {}", value))
+    }
+}
+
+fn parse_toml(text: &str) -> Result<Vec<Entry>, (usize, String)> {
+    let mut entries: Vec<Entry> = Vec::new();
+    let mut section = String::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = strip_toml_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with('[') {
+            if !line.ends_with(']') || line.starts_with("[[") {
+                return Err((i + 1, format!("Invalid table {}", line)));
+            }
+            section = unquote(line[1..line.len() - 1].trim()).to_lowercase();
+            continue;
+        }
+        let separator = line.find('=').ok_or((i + 1, format!("Expected key = value, found {:?}", line)))?;
+        entries.push(Entry {
+            section: section.clone(),
+            key: unquote(line[..separator].trim()).to_string(),
+            value: parse_toml_value(line[separator + 1..].trim()).map_err(|err| (i + 1, err))?,
+            line: i + 1,
+        });
+    }
+    Ok(entries)
+}
+
+/// Just enough JSON for an object whose values are objects of scalars
+struct JsonParser {
+    chars: Vec<char>,
+    position: usize,
+    line: usize,
+}
+
+impl JsonParser {
+    fn error<T>(&self, message: &str) -> Result<T, (usize, String)> {
+        Err((self.line, message.to_string()))
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(&c) = self.chars.get(self.position) {
+            if !c.is_whitespace() {
+                break;
+            }
+            if c == '\n' {
+                self.line += 1;
+            }
+            self.position += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_whitespace();
+        self.chars.get(self.position).copied()
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), (usize, String)> {
+        if self.peek() != Some(expected) {
+            return self.error(&format!("Expected {}", expected));
+        }
+        self.position += 1;
+        Ok(())
+    }
+
+    fn string(&mut self) -> Result<String, (usize, String)> {
+        self.expect('"')?;
+        let mut str = String::new();
+        loop {
+            let c = match self.chars.get(self.position) {
+                None | Some('\n') => return self.error("Missing closing quote"),
+                Some(&c) => c
+            };
+            self.position += 1;
+            match c {
+                '"' => return Ok(str),
+                '\\' => {
+                    let escaped = self.chars.get(self.position).copied();
+                    self.position += 1;
+                    match escaped {
+                        Some('n') => str.push('\n'),
+                        Some('t') => str.push('\t'),
+                        Some('r') => str.push('\r'),
+                        Some('"') => str.push('"'),
+                        Some('\\') => str.push('\\'),
+                        Some('/') => str.push('/'),
+                        _ => return self.error("Invalid escape sequence")
+                    }
+                }
+                _ => str.push(c)
+            }
+        }
+    }
+
+    fn scalar(&mut self) -> Result<Value, (usize, String)> {
+        if self.peek() == Some('"') {
+            return Ok(Value::Str(self.string()?));
+        }
+        let start = self.position;
+        while let Some(&c) = self.chars.get(self.position) {
+            if !(c.is_ascii_alphanumeric() || c == '-' || c == '+' || c == '.') {
+                break;
+            }
+            self.position += 1;
+        }
+        let literal: String = self.chars[start..self.position].iter().collect();
+        match literal.as_str() {
+            "true" => Ok(Value::Bool(true)),
+            "false" => Ok(Value::Bool(false)),
+            _ => match literal.parse::<i64>() {
+                Ok(val) => Ok(Value::Int(val)),
+                Err(_) => self.error(&format!("Unsupported value {:?}", literal))
+            }
+        }
+    }
+
+    /// Calls `on_entry` for every member of the object starting at the current position
+    fn object(&mut self, mut on_entry: impl FnMut(&mut Self, String, usize) -> Result<(), (usize, String)>) -> Result<(), (usize, String)> {
+        self.expect('{')?;
+        if self.peek() == Some('}') {
+            self.position += 1;
+            return Ok(());
+        }
+        loop {
+            let line = {
+                self.skip_whitespace();
+                self.line
+            };
+            let key = self.string()?;
+            self.expect(':')?;
+            on_entry(self, key, line)?;
+            match self.peek() {
+                Some(',') => self.position += 1,
+                Some('}') => {
+                    self.position += 1;
+                    return Ok(());
+                }
+                _ => return self.error("Expected , or }")
+            }
+        }
+    }
+}
+
+fn parse_json(text: &str) -> Result<Vec<Entry>, (usize, String)> {
+    let mut parser = JsonParser { chars: text.chars().collect(), position: 0, line: 1 };
+    let mut entries: Vec<Entry> = Vec::new();
+    parser.object(|parser, section, line| {
+        if parser.peek() != Some('{') {
+            let value = parser.scalar()?;
+            entries.push(Entry { section: String::new(), key: section, value, line });
+            return Ok(());
+        }
+        let section = section.to_lowercase();
+        parser.object(|parser, key, line| {
+            if parser.peek() == Some('{') || parser.peek() == Some('[') {
+                return parser.error("Only one level of nested objects is supported");
+            }
+            let value = parser.scalar()?;
+            entries.push(Entry { section: section.clone(), key, value, line });
+            Ok(())
+        })
+    })?;
+    if parser.peek().is_some() {
+        return parser.error("Unexpected content after the end of the object");
+    }
+    Ok(entries)
+}
+
+/// Reads the entries of a configuration file, the format is chosen from the extension and defaults to INI.
+/// Errors contain the file and the line
+pub fn load(path: &str) -> Result<Vec<Entry>, String> {
+    let text = fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+    let entries = match Format::of(path) {
+        Format::Ini => parse_ini(&text),
+        Format::Toml => parse_toml(&text),
+        Format::Json => parse_json(&text)
+    };
+    entries.map_err(|(line, err)| format!("{}:{}: {}", path, line, err))
+}
+
+/// Writes string values of a single section in the format chosen from the extension of `path`
+pub fn serialize(path: &str, section: &str, values: &[(String, String)]) -> String {
+    let mut text = String::new();
+    match Format::of(path) {
+        Format::Ini => {
+            text.push_str(&format!("[{}]\n", section));
+            for (key, value) in values {
+                text.push_str(&format!("{}={}\n", key, value));
+            }
+        }
+        Format::Toml => {
+            text.push_str(&format!("[{}]\n", section));
+            for (key, value) in values {
+                text.push_str(&format!("{} = {:?}\n", key, value));
+            }
+        }
+        Format::Json => {
+            let values: Vec<String> = values.iter().map(|(key, value)| format!("    {:?}: {:?}", key, value)).collect();
+            text.push_str(&format!("{{\n  {:?}: {{\n{}\n  }}\n}}\n", section, values.join(",\n")));
+        }
+    }
+    text
+}
+
+/// JSON string literal of `str`
+pub fn json_string(str: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in str.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c)
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn parse_component(component: &str) -> Option<u8> {
+    component.trim().parse::<u8>().ok()
+}
+
+/// Parses `rrggbb`, `rgb`, `#rrggbb`, `#rgb`, `rgb(r, g, b)` or an integer
+pub fn parse_color(value: &Value) -> Result<Color, String> {
+    let str = match value {
+        Value::Int(val) if (0..=0xffffff).contains(val) => return Ok(Color::from(*val as u32)),
+        Value::Str(str) => str.trim(),
+        _ => return Err(format!("Invalid color {:?}", value))
+    };
+    let lowercase = str.to_lowercase();
+    if let Some(components) = lowercase.strip_prefix("rgb(").and_then(|rest| rest.strip_suffix(')')) {
+        let components: Vec<Option<u8>> = components.split(',').map(parse_component).collect();
+        return match components.as_slice() {
+            [Some(r), Some(g), Some(b)] => Ok(Color::new(*r, *g, *b)),
+            _ => Err(format!("Invalid color {:?}, components must be 3 integers between 0 and 255", str))
+        };
+    }
+    let mut hex = lowercase.trim_start_matches('#').to_string();
+    if hex.len() == 3 {
+        hex = hex.chars().flat_map(|c| [c, c]).collect();
+    }
+    if hex.len() != 6 {
+        return Err(format!("Invalid color {:?}", str));
+    }
+    u32::from_str_radix(&hex, 16).map(Color::from).map_err(|_| format!("Invalid color {:?}", str))
+}
+
+#[cfg(test)]
+mod config_tests {
+    use crate::instructions::Instruction;
+    use crate::params::Params;
+
+    use super::*;
+
+    fn entry(section: &str, key: &str, value: Value, line: usize) -> Entry {
+        Entry { section: section.to_string(), key: key.to_string(), value, line }
+    }
+
+    #[test]
+    fn ini() {
+        let entries = parse_ini("; comment\n[Colors]\nSUM = #fff\n\nsub:\"rgb(1,2,3)\"\nAND=").unwrap();
+        assert_eq!(vec![
+            entry("colors", "SUM", Value::Str("#fff".to_string()), 3),
+            entry("colors", "sub", Value::Str("rgb(1,2,3)".to_string()), 5),
+            entry("colors", "AND", Value::Str("".to_string()), 6),
+        ], entries);
+        assert_eq!(2, parse_ini("[colors]\nSUM").unwrap_err().0);
+    }
+
+    #[test]
+    fn toml() {
+        let entries = parse_toml("# palette\n[COLORS]\nsum = \"#00ced1\" # comment\n\"sub\" = 0xffa500\nmul = 'a#b'").unwrap();
+        assert_eq!(vec![
+            entry("colors", "sum", Value::Str("#00ced1".to_string()), 3),
+            entry("colors", "sub", Value::Int(0xffa500), 4),
+            entry("colors", "mul", Value::Str("a#b".to_string()), 5),
+        ], entries);
+        assert_eq!(3, parse_toml("[colors]\n\nsum = [1, [2]]").unwrap_err().0);
+        let entries = parse_toml("dirs = [\"a,b\", 'c', 3,]\nempty = []").unwrap();
+        assert_eq!(Value::List(vec![Value::Str("a,b".to_string()), Value::Str("c".to_string()), Value::Int(3)]), entries[0].value);
+        assert_eq!(Value::List(vec![]), entries[1].value);
+    }
+
+    #[test]
+    fn json() {
+        let entries = parse_json("{\n  \"Colors\": {\n    \"SUM\": \"#fff\",\n    \"SUB\": 16777215\n  },\n  \"x\": true\n}").unwrap();
+        assert_eq!(vec![
+            entry("colors", "SUM", Value::Str("#fff".to_string()), 3),
+            entry("colors", "SUB", Value::Int(0xffffff), 4),
+            entry("", "x", Value::Bool(true), 6),
+        ], entries);
+        assert_eq!(3, parse_json("{\"colors\": {\n\"SUM\": \"#fff\",\n\"SUB\" \"#000\"}}").unwrap_err().0);
+        assert!(parse_json("{\"colors\": {\"SUM\": {}}}").is_err());
+        assert!(parse_json("{} x").is_err());
+    }
+
+    #[test]
+    fn serialize_entries() {
+        let values = vec![("SUM".to_string(), "#00ced1".to_string()), ("SUB".to_string(), "#ffa500".to_string())];
+        for path in ["out.ini", "out.toml", "out.json", ""] {
+            let entries = match Format::of(path) {
+                Format::Ini => parse_ini(&serialize(path, "colors", &values)),
+                Format::Toml => parse_toml(&serialize(path, "colors", &values)),
+                Format::Json => parse_json(&serialize(path, "colors", &values))
+            }.unwrap();
+            let read: Vec<(String, Value)> = entries.into_iter().map(|entry| (entry.key, entry.value)).collect();
+            assert_eq!(vec![
+                ("SUM".to_string(), Value::Str("#00ced1".to_string())),
+                ("SUB".to_string(), Value::Str("#ffa500".to_string())),
+            ], read, "{}", path);
+        }
+    }
+
+    #[test]
+    fn colors() {
+        let white = Color::from(0xffffffu32);
+        assert_eq!(white, parse_color(&Value::Str("fff".to_string())).unwrap());
+        assert_eq!(white, parse_color(&Value::Str("#FFFFFF".to_string())).unwrap());
+        assert_eq!(white, parse_color(&Value::Str("rgb(255, 255,255)".to_string())).unwrap());
+        assert_eq!(white, parse_color(&Value::Int(0xffffff)).unwrap());
+        assert_eq!(Color::new(0x12, 0x34, 0x56), parse_color(&Value::Str("#123456".to_string())).unwrap());
+        assert!(parse_color(&Value::Str("rgb(256, 0, 0)".to_string())).is_err());
+        assert!(parse_color(&Value::Str("#ffff".to_string())).is_err());
+        assert!(parse_color(&Value::Str("#gggggg".to_string())).is_err());
+        assert!(parse_color(&Value::Int(-1)).is_err());
+        assert!(parse_color(&Value::Bool(true)).is_err());
+    }
+
+    #[test]
+    fn read_config_file() {
+        let mut params = Params { ini_path: Some("tests/conf.ini".to_string()), is_random: false, ..Default::default() };
+        params.read_config().unwrap();
+        assert_eq!(vec![Color::from(0x010000u32)], params.get_color(Instruction::InputInt).unwrap());
+        assert_eq!(vec![Color::from(0xfffeffu32)], params.get_color(Instruction::Not).unwrap());
+        assert_eq!(Instruction::Sum.get_default_colors(&params).unwrap(), params.get_color(Instruction::Sum).unwrap());
+    }
+}
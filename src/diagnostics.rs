@@ -0,0 +1,164 @@
+//! Errors, warnings and build summaries printed by the commands.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use strum_macros::{EnumString, IntoStaticStr};
+
+use crate::assembler::Summary;
+use crate::config::json_string;
+use crate::error::{Error, Location};
+
+#[derive(Clone, Copy, Debug, PartialEq, EnumString)]
+#[strum(serialize_all = "lowercase")]
+pub enum MessageFormat {
+    Human,
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, IntoStaticStr)]
+#[strum(serialize_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+pub fn set_format(format: &str) -> Result<(), Error> {
+    let format: MessageFormat = format.parse()
+        .map_err(|_| Error::Usage(format!("Invalid message format {}, expected human or json", format)))?;
+    JSON.store(format == MessageFormat::Json, Ordering::SeqCst);
+    Ok(())
+}
+
+pub fn is_json() -> bool {
+    JSON.load(Ordering::SeqCst)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: &'static str,
+    pub message: String,
+    /// File the diagnostic is about, also when the location inside it is unknown
+    pub file: Option<String>,
+    pub location: Option<Location>,
+}
+
+impl Diagnostic {
+    pub fn from_error(err: &Error) -> Self {
+        let location = err.location().cloned();
+        Diagnostic {
+            severity: Severity::Error,
+            code: err.code(),
+            message: err.message().to_string(),
+            file: location.as_ref().map(|location| location.file.clone()),
+            location,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let optional = |value: Option<String>| value.unwrap_or_else(|| "null".to_string());
+        let location = self.location.as_ref();
+        format!("{{\"type\":\"diagnostic\",\"severity\":\"{}\",\"code\":{},\"message\":{},\"file\":{},\"line\":{},\"column\":{},\"span\":{}}}",
+                <&str>::from(self.severity), json_string(self.code), json_string(&self.message),
+                optional(self.file.as_deref().map(json_string)),
+                optional(location.map(|location| location.line.to_string())),
+                optional(location.map(|location| location.column.to_string())),
+                optional(location.map(|location| format!("{{\"start\":{},\"end\":{}}}", location.span.0, location.span.1))))
+    }
+}
+
+/// Prints an error, as `error: message` or as a JSON diagnostic
+pub fn error(err: &Error) {
+    if is_json() {
+        println!("{}", Diagnostic::from_error(err).to_json());
+    } else {
+        eprintln!("error: {}", err);
+    }
+}
+
+/// Prints a warning about `file`, as `warning: file: message` or as a JSON diagnostic
+pub fn warning(code: &'static str, file: &str, message: String) {
+    if is_json() {
+        let diagnostic = Diagnostic { severity: Severity::Warning, code, message, file: Some(file.to_string()), location: None };
+        println!("{}", diagnostic.to_json());
+    } else {
+        eprintln!("warning: {}: {}", file, message);
+    }
+}
+
+/// Prints a progress message, only with the human readable format
+pub fn note(message: String) {
+    if !is_json() {
+        println!("{}", message);
+    }
+}
+
+pub fn summary_json(input: &str, output: &str, summary: &Summary) -> String {
+    let stats = summary.stats.as_ref().map(|stats| format!(",\"stats\":{}", stats.to_json())).unwrap_or_default();
+    let png = summary.png.as_ref().map(|png| format!(",\"png\":{}", png.to_json())).unwrap_or_default();
+    format!("{{\"type\":\"summary\",\"input\":{},\"output\":{},\"pixels\":{},\"width\":{},\"height\":{},\"padding\":{}{}{}}}",
+            json_string(input), json_string(output), summary.pixels, summary.width, summary.height, summary.padding, stats, png)
+}
+
+/// Human readable details of an assembled program: the statistics, the PNG format when they are on or
+/// when an indexed image was kept
+fn summary_text(summary: &Summary) -> String {
+    let mut text = String::new();
+    if let Some(stats) = &summary.stats {
+        text.push_str(&stats.to_text());
+        text.push_str(&format!("Image: {}x{}, {} pixels and {} padding pixels\n", summary.width, summary.height, summary.pixels, summary.padding));
+    }
+    match &summary.png {
+        Some(png) if summary.stats.is_some() || png.palette.is_some() => text.push_str(&png.to_text()),
+        _ => {}
+    }
+    text
+}
+
+/// Prints the summary of an assembled program with the JSON format, `message` and the details with the
+/// human readable one
+pub fn summary(input: &str, output: &str, summary: &Summary, message: Option<String>) {
+    if is_json() {
+        println!("{}", summary_json(input, output, summary));
+        return;
+    }
+    if let Some(message) = message {
+        println!("{}", message);
+    }
+    print!("{}", summary_text(summary));
+}
+
+#[cfg(test)]
+mod diagnostics_tests {
+    use crate::image::PngSummary;
+
+    use super::*;
+
+    #[test]
+    fn diagnostics() {
+        let location = Location { file: "a\"b.vasm".to_string(), line: 2, column: 3, span: (6, 15) };
+        let err = Error::instruction("Instruction not found", location);
+        assert_eq!("{\"type\":\"diagnostic\",\"severity\":\"error\",\"code\":\"unknown-instruction\",\"message\":\"Instruction not found\",\
+                    \"file\":\"a\\\"b.vasm\",\"line\":2,\"column\":3,\"span\":{\"start\":6,\"end\":15}}",
+                   Diagnostic::from_error(&err).to_json());
+        let warning = Diagnostic { severity: Severity::Warning, code: "duplicate-color", message: "x\ny".to_string(), file: None, location: None };
+        assert_eq!("{\"type\":\"diagnostic\",\"severity\":\"warning\",\"code\":\"duplicate-color\",\"message\":\"x\\ny\",\
+                    \"file\":null,\"line\":null,\"column\":null,\"span\":null}", warning.to_json());
+    }
+
+    #[test]
+    fn summaries() {
+        let summary = Summary { pixels: 5, width: 30, height: 10, padding: 1, stats: None, png: None };
+        assert_eq!("{\"type\":\"summary\",\"input\":\"in.vasm\",\"output\":\"out.png\",\"pixels\":5,\"width\":30,\"height\":10,\"padding\":1}",
+                   summary_json("in.vasm", "out.png", &summary));
+        assert!(set_format("xml").is_err());
+        assert_eq!("", summary_text(&summary));
+        let png = PngSummary { palette: Some(4), bit_depth: 2, bytes: 100, saved: 40 };
+        let indexed = Summary { png: Some(png.clone()), ..summary.clone() };
+        assert_eq!("PNG: indexed with 4 colors at 2 bits per pixel, 100 bytes, 40 bytes smaller than RGB\n", summary_text(&indexed));
+        let rgb = Summary { png: Some(PngSummary { palette: None, bit_depth: 8, saved: 0, ..png }), ..summary };
+        assert_eq!("", summary_text(&rgb));
+    }
+}
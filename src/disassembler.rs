@@ -0,0 +1,62 @@
+//! Translation of images back into VASM.
+
+use std::collections::HashMap;
+
+use crate::color::Color;
+use crate::instructions::Instruction;
+use crate::params::Params;
+
+/// Text shown on a pixel: the name of its instruction or the value it pushes
+pub fn label(table: &HashMap<Color, Instruction>, color: &Color) -> String {
+    match table.get(color) {
+        Some(instruction) => instruction.name().to_string(),
+        None => color.value().to_string()
+    }
+}
+
+pub fn disassemble(colors: &[Color], conf: &Params) -> Vec<Instruction> {
+    let table = conf.color_table();
+    let mut instructions: Vec<Instruction> = colors.iter()
+        .map(|color| table.get(color).cloned().unwrap_or_else(|| {
+            let (r, g, b) = color.components();
+            Instruction::RawColor(r, g, b)
+        }))
+        .collect();
+    while instructions.last() == Some(&Instruction::Quit) {
+        instructions.pop();
+    }
+    instructions
+}
+
+#[cfg(test)]
+mod disassembler_tests {
+    use crate::assembler;
+
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let params = Params::test();
+        let program = vec![Instruction::Println("Hi".to_string()), Instruction::RawInt(1000), Instruction::OutputInt];
+        let colors = assembler::instructions_to_colors(&params, program).unwrap();
+        let instructions = disassemble(&colors, &params);
+        assert_eq!(Some(&Instruction::OutputInt), instructions.last());
+        assert!(instructions.contains(&Instruction::While));
+        assert_eq!(colors, assembler::instructions_to_colors(&params, instructions).unwrap());
+    }
+
+    #[test]
+    fn padding() {
+        let params = Params::test();
+        let colors = assembler::instructions_to_colors(&params, vec![Instruction::Dup, Instruction::Quit, Instruction::Quit]).unwrap();
+        assert_eq!(vec![Instruction::Dup], disassemble(&colors, &params));
+    }
+
+    #[test]
+    fn labels() {
+        let params = Params::test();
+        let table = params.color_table();
+        assert_eq!("DUP", label(&table, &params.opcode_color(Instruction::Dup)));
+        assert_eq!("300", label(&table, &Color::not_random(300)));
+    }
+}
@@ -0,0 +1,161 @@
+use std::fmt;
+
+use crate::parser;
+
+pub const EXIT_PARSE: i32 = 1;
+pub const EXIT_USAGE: i32 = 2;
+pub const EXIT_CONFIG: i32 = 3;
+pub const EXIT_IO: i32 = 4;
+pub const EXIT_RUNTIME: i32 = 5;
+
+/// Position of an error in a source file. Lines and columns start from 1,
+/// the span is the range of bytes of the file covered by the error
+#[derive(Clone, Debug, PartialEq)]
+pub struct Location {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    pub span: (usize, usize),
+}
+
+impl Location {
+    /// Location of the code written in `text`, the line `line` starting at the byte `offset` of the file
+    pub fn in_line(file: impl fmt::Display, line: usize, offset: usize, text: &str) -> Self {
+        let (code, _) = parser::split_comment(text);
+        let start = code.len() - code.trim_start().len();
+        let end = code.trim_end().len().max(start);
+        Location { file: file.to_string(), line, column: text[..start].chars().count() + 1, span: (offset + start, offset + end) }
+    }
+
+    /// Location of the line `line` of `source`
+    pub fn of_line(file: impl fmt::Display, source: &str, line: usize) -> Self {
+        let offset: usize = source.split_inclusive('\n').take(line.saturating_sub(1)).map(str::len).sum();
+        let text = source.lines().nth(line.saturating_sub(1)).unwrap_or("");
+        Location::in_line(file, line, offset, text)
+    }
+}
+
+/// Invalid input program, `code` names the kind of error
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError {
+    pub code: &'static str,
+    pub message: String,
+    pub location: Option<Location>,
+}
+
+/// Errors reported by the commands, every kind exits with its own code
+#[derive(Clone, Debug, PartialEq)]
+pub enum Error {
+    /// The input program is invalid
+    Parse(ParseError),
+    /// Wrong or missing command line arguments
+    Usage(String),
+    /// The config file or the project manifest is invalid
+    Config(String),
+    /// A file can't be read or written
+    Io(String),
+    /// The program failed while running
+    Runtime(String),
+}
+
+impl Error {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Parse(_) => EXIT_PARSE,
+            Error::Usage(_) => EXIT_USAGE,
+            Error::Config(_) => EXIT_CONFIG,
+            Error::Io(_) => EXIT_IO,
+            Error::Runtime(_) => EXIT_RUNTIME
+        }
+    }
+
+    /// Name of the kind of error, reported with `--message-format json`
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::Parse(err) => err.code,
+            Error::Usage(_) => "usage",
+            Error::Config(_) => "config",
+            Error::Io(_) => "io",
+            Error::Runtime(_) => "runtime"
+        }
+    }
+
+    /// Where the error is in the input, when known
+    pub fn location(&self) -> Option<&Location> {
+        match self {
+            Error::Parse(err) => err.location.as_ref(),
+            _ => None
+        }
+    }
+
+    /// Message without the location
+    pub fn message(&self) -> &str {
+        match self {
+            Error::Parse(ParseError { message, .. }) | Error::Usage(message) | Error::Config(message)
+            | Error::Io(message) | Error::Runtime(message) => message
+        }
+    }
+
+    /// Error of the same kind with another message
+    pub fn with_message(&self, message: String) -> Self {
+        match self {
+            Error::Parse(err) => Error::Parse(ParseError { code: err.code, message, location: None }),
+            Error::Usage(_) => Error::Usage(message),
+            Error::Config(_) => Error::Config(message),
+            Error::Io(_) => Error::Io(message),
+            Error::Runtime(_) => Error::Runtime(message)
+        }
+    }
+
+    /// Invalid program without a location
+    pub fn parse(code: &'static str, message: impl fmt::Display) -> Self {
+        Error::Parse(ParseError { code, message: message.to_string(), location: None })
+    }
+
+    /// Invalid program at `location`
+    pub fn parse_at(code: &'static str, message: impl fmt::Display, location: Location) -> Self {
+        Error::Parse(ParseError { code, message: message.to_string(), location: Some(location) })
+    }
+
+    /// Error of an instruction that can't be parsed, the code depends on the message
+    pub fn instruction(message: &str, location: Location) -> Self {
+        let code = match message {
+            "Instruction not found" => "unknown-instruction",
+            "Wrong number of arguments" => "argument-count",
+            "Invalid instruction" => "invalid-syntax",
+            _ => "invalid-argument"
+        };
+        Error::parse_at(code, message, location)
+    }
+
+    /// IO error on `path`
+    pub fn io(path: impl fmt::Display, err: impl fmt::Display) -> Self {
+        Error::Io(format!("{}: {}", path, err))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.location() {
+            Some(location) => write!(f, "Invalid input file {} at line {}: {}", location.file, location.line, self.message()),
+            None => write!(f, "{}", self.message())
+        }
+    }
+}
+
+#[cfg(test)]
+mod error_tests {
+    use super::*;
+
+    #[test]
+    fn locations() {
+        let source = "DUP\n  RAW_INT x # comment\n";
+        let location = Location::of_line("a.vasm", source, 2);
+        assert_eq!(Location { file: "a.vasm".to_string(), line: 2, column: 3, span: (6, 15) }, location);
+        assert_eq!("RAW_INT x", &source[location.span.0..location.span.1]);
+        let err = Error::instruction("Instruction not found", location);
+        assert_eq!("unknown-instruction", err.code());
+        assert_eq!("Invalid input file a.vasm at line 2: Instruction not found", err.to_string());
+        assert_eq!(EXIT_PARSE, err.exit_code());
+    }
+}
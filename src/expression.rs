@@ -0,0 +1,286 @@
+use crate::instructions::Instruction;
+use crate::parser;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Token {
+    Number(i32),
+    Name(String),
+    Str(String),
+    Operator(&'static str),
+    Open,
+    Close,
+    BlockOpen,
+    BlockClose,
+    Assign,
+    Separator,
+}
+
+/// Tokens of a source together with the line of each of them
+pub type Tokens = (Vec<Token>, Vec<usize>);
+
+const OPERATORS: [&str; 12] = ["<<", ">>", "+", "-", "*", "/", "%", "&", "|", "^", "~", "!"];
+
+fn consume_quoted(chars: &[char], i: &mut usize) -> Result<String, &'static str> {
+    let mut str = String::new();
+    *i += 1;
+    loop {
+        match chars.get(*i) {
+            None => return Err("Missing closing quote"),
+            Some('"') => {
+                *i += 1;
+                return Ok(str);
+            }
+            Some('\\') => {
+                *i += 1;
+                str.push(parser::escaped(chars.get(*i).copied()).ok_or("Invalid escape sequence")?);
+            }
+            Some(&c) => str.push(c)
+        }
+        *i += 1;
+    }
+}
+
+/// Splits the source in tokens, returning the line of each token too.
+/// `#` starts a comment that lasts until the end of the line, new lines are separators like `;`
+pub fn tokenize(source: &str) -> Result<Tokens, (usize, &'static str)> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut lines: Vec<usize> = Vec::new();
+    let mut line = 1;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let token = match c {
+            '\n' => {
+                tokens.push(Token::Separator);
+                lines.push(line);
+                line += 1;
+                i += 1;
+                continue;
+            }
+            '#' => {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+                continue;
+            }
+            _ if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            '(' | ')' | '{' | '}' | '=' | ';' => {
+                i += 1;
+                match c {
+                    '(' => Token::Open,
+                    ')' => Token::Close,
+                    '{' => Token::BlockOpen,
+                    '}' => Token::BlockClose,
+                    '=' => Token::Assign,
+                    _ => Token::Separator
+                }
+            }
+            '"' => Token::Str(consume_quoted(&chars, &mut i).map_err(|err| (line, err))?),
+            _ if c.is_ascii_digit() => {
+                let start = i;
+                while i < chars.len() && chars[i].is_ascii_alphanumeric() {
+                    i += 1;
+                }
+                let literal: String = chars[start..i].iter().collect();
+                let value = match literal.strip_prefix("0x") {
+                    Some(hex) => u32::from_str_radix(hex, 16).map(|val| val as i32).ok(),
+                    None => literal.parse::<i32>().ok()
+                };
+                Token::Number(value.ok_or((line, "Invalid number in expression"))?)
+            }
+            _ if c.is_alphabetic() || c == '_' => {
+                let start = i;
+                while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                Token::Name(chars[start..i].iter().collect())
+            }
+            _ => {
+                let rest: String = chars[i..].iter().take(2).collect();
+                let operator = OPERATORS.iter().find(|op| rest.starts_with(*op)).ok_or((line, "Invalid character in expression"))?;
+                i += operator.len();
+                Token::Operator(operator)
+            }
+        };
+        tokens.push(token);
+        lines.push(line);
+    }
+    Ok((tokens, lines))
+}
+
+fn binary_instruction(operator: &str) -> Option<(u8, Instruction)> {
+    match operator {
+        "|" => Some((1, Instruction::Or)),
+        "^" => Some((2, Instruction::Xor)),
+        "&" => Some((3, Instruction::And)),
+        "<<" => Some((4, Instruction::Lshift)),
+        ">>" => Some((4, Instruction::RShift)),
+        "+" => Some((5, Instruction::Sum)),
+        "-" => Some((5, Instruction::Sub)),
+        "*" => Some((6, Instruction::Mul)),
+        "/" => Some((6, Instruction::Div)),
+        "%" => Some((6, Instruction::Mod)),
+        _ => None
+    }
+}
+
+/// Compiles the expression starting at `position`, leaving `position` after its last token.
+/// `variable` returns the instructions that push a copy of a variable, `None` if it doesn't exist
+pub struct Compiler<'a> {
+    tokens: &'a [Token],
+    pub position: usize,
+    variable: &'a dyn Fn(&str) -> Option<Vec<Instruction>>,
+    output: Vec<Instruction>,
+}
+
+impl<'a> Compiler<'a> {
+    pub fn new(tokens: &'a [Token], position: usize, variable: &'a dyn Fn(&str) -> Option<Vec<Instruction>>) -> Self {
+        Compiler { tokens, position, variable, output: Vec::new() }
+    }
+
+    pub fn compile(&mut self) -> Result<Vec<Instruction>, &'static str> {
+        self.output.clear();
+        self.binary(0)?;
+        Ok(std::mem::take(&mut self.output))
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn expect_close(&mut self) -> Result<(), &'static str> {
+        match self.next() {
+            Some(Token::Close) => Ok(()),
+            _ => Err("Missing closing parenthesis in expression")
+        }
+    }
+
+    fn unary(&mut self) -> Result<(), &'static str> {
+        match self.next() {
+            Some(Token::Number(val)) => self.output.push(Instruction::RawInt(val)),
+            Some(Token::Open) => {
+                self.binary(0)?;
+                self.expect_close()?;
+            }
+            Some(Token::Name(name)) if name.to_uppercase() == "RND" => {
+                if self.next() != Some(Token::Open) {
+                    return Err("RND must be followed by its argument in parenthesis");
+                }
+                self.binary(0)?;
+                self.expect_close()?;
+                self.output.push(Instruction::Rnd);
+            }
+            Some(Token::Name(name)) => {
+                let instructions = (self.variable)(&name).ok_or("Unknown name in expression")?;
+                self.output.extend(instructions);
+            }
+            Some(Token::Operator("-")) => {
+                if let Some(Token::Number(val)) = self.peek() {
+                    let val = val.wrapping_neg();
+                    self.position += 1;
+                    self.output.push(Instruction::RawInt(val));
+                } else {
+                    self.output.push(Instruction::RawInt(0));
+                    self.unary()?;
+                    self.output.push(Instruction::Sub);
+                }
+            }
+            Some(Token::Operator("+")) => self.unary()?,
+            Some(Token::Operator("~")) | Some(Token::Operator("!")) => {
+                self.unary()?;
+                self.output.push(Instruction::Not);
+            }
+            _ => return Err("Expected a value in expression")
+        }
+        Ok(())
+    }
+
+    /// Precedence climbing, operators with the same precedence are left associative
+    fn binary(&mut self, min_precedence: u8) -> Result<(), &'static str> {
+        self.unary()?;
+        while let Some(Token::Operator(operator)) = self.peek() {
+            let (precedence, instruction) = match binary_instruction(operator) {
+                Some(val) => val,
+                None => return Err("Invalid operator in expression")
+            };
+            if precedence <= min_precedence {
+                break;
+            }
+            self.position += 1;
+            self.binary(precedence)?;
+            self.output.push(instruction);
+        }
+        Ok(())
+    }
+}
+
+/// Compiles an infix integer expression into the instructions that leave its value on top of the stack
+pub fn compile(expression: &str) -> Result<Vec<Instruction>, &'static str> {
+    let (tokens, _) = tokenize(expression).map_err(|(_, err)| err)?;
+    let mut compiler = Compiler::new(&tokens, 0, &|_| None);
+    let instructions = compiler.compile()?;
+    if compiler.position != tokens.len() {
+        return Err("Unexpected token in expression");
+    }
+    Ok(instructions)
+}
+
+#[cfg(test)]
+mod expression_tests {
+    use super::*;
+
+    #[test]
+    fn precedence() {
+        assert_eq!(vec![Instruction::RawInt(1), Instruction::RawInt(2), Instruction::RawInt(3), Instruction::Mul, Instruction::Sum],
+                   compile("1 + 2 * 3").unwrap());
+        assert_eq!(vec![Instruction::RawInt(1), Instruction::RawInt(2), Instruction::Sum, Instruction::RawInt(3), Instruction::Mul],
+                   compile("(1 + 2) * 3").unwrap());
+        assert_eq!(vec![Instruction::RawInt(1), Instruction::RawInt(2), Instruction::Sub, Instruction::RawInt(3), Instruction::Sub],
+                   compile("1 - 2 - 3").unwrap());
+        assert_eq!(vec![Instruction::RawInt(1), Instruction::RawInt(2), Instruction::Lshift, Instruction::RawInt(3), Instruction::Or],
+                   compile("1<<2|3").unwrap());
+    }
+
+    #[test]
+    fn unary_and_rnd() {
+        assert_eq!(vec![Instruction::RawInt(-5)], compile("-5").unwrap());
+        assert_eq!(vec![Instruction::RawInt(0), Instruction::RawInt(10), Instruction::Rnd, Instruction::Sub],
+                   compile("-RND(10)").unwrap());
+        assert_eq!(vec![Instruction::RawInt(255), Instruction::Not, Instruction::RawInt(7), Instruction::Xor],
+                   compile("~0xff ^ 7").unwrap());
+    }
+
+    #[test]
+    fn variables() {
+        let variable = |name: &str| if name == "x" { Some(vec![Instruction::Dup]) } else { None };
+        let (tokens, _) = tokenize("x * 2 y").unwrap();
+        let mut compiler = Compiler::new(&tokens, 0, &variable);
+        assert_eq!(vec![Instruction::Dup, Instruction::RawInt(2), Instruction::Mul], compiler.compile().unwrap());
+        assert_eq!(3, compiler.position);
+        let mut compiler = Compiler::new(&tokens, 3, &variable);
+        assert!(compiler.compile().is_err());
+    }
+
+    #[test]
+    fn invalid_expressions() {
+        assert!(compile("").is_err());
+        assert!(compile("1 +").is_err());
+        assert!(compile("(1").is_err());
+        assert!(compile("1 2").is_err());
+        assert!(compile("x + 1").is_err());
+        assert!(compile("RND 3").is_err());
+        assert!(compile("1 ~ 2").is_err());
+        assert!(compile("1 $ 2").is_err());
+        assert!(compile("1 = 2").is_err());
+    }
+}
@@ -0,0 +1,98 @@
+//! Formatter for VASM sources.
+
+use crate::instructions::Instruction;
+use crate::parser;
+
+const INDENT: &str = "    ";
+
+/// Canonical text of the instruction written in `code`
+fn format_instruction(code: &str, instruction: &Instruction) -> String {
+    match instruction {
+        // The compiled expression isn't the text written by the user
+        Instruction::Expr(_) => {
+            let tokens = parser::parse(code).unwrap_or_default();
+            format!("EXPR {}", tokens[1..].join(" "))
+        }
+        _ => instruction.to_command()
+    }
+}
+
+/// Formats a VASM source, errors contain the line
+pub fn format(source: &str) -> Result<String, (usize, &'static str)> {
+    let mut lines: Vec<String> = Vec::new();
+    let mut depth = 0usize;
+    for (i, line) in source.lines().enumerate() {
+        let (code, comment) = parser::split_comment(line);
+        let instruction = Instruction::from_command(code.trim()).map_err(|err| (i + 1, err))?;
+        if instruction == Some(Instruction::WhileEnd) {
+            depth = depth.saturating_sub(1);
+        }
+        let mut formatted = INDENT.repeat(depth);
+        if let Some(instruction) = &instruction {
+            formatted.push_str(&format_instruction(code.trim(), instruction));
+        }
+        if let Some(comment) = comment {
+            if instruction.is_some() {
+                formatted.push(' ');
+            }
+            formatted.push('#');
+            formatted.push_str(comment.trim_end());
+        }
+        if instruction == Some(Instruction::While) {
+            depth += 1;
+        }
+        let formatted = formatted.trim_end().to_string();
+        if !formatted.is_empty() || lines.last().is_some_and(|last| !last.is_empty()) {
+            lines.push(formatted);
+        }
+    }
+    while lines.last().is_some_and(|last| last.is_empty()) {
+        lines.pop();
+    }
+    Ok(lines.into_iter().map(|line| line + "\n").collect())
+}
+
+#[cfg(test)]
+mod formatter_tests {
+    use crate::assembler;
+    use crate::color::Color;
+    use crate::params::Params;
+
+    use super::*;
+
+    #[test]
+    fn indentation() {
+        let source = "# counter\nRAW_INT  3\n\n\n  WHILE   # loop\nDUP\nWHILE\n\t\tOUTPUT_INT\nWHILE_END\nWHILE_END\n\n";
+        let expected = "# counter\nRAW_INT 3\n\nWHILE # loop\n    DUP\n    WHILE\n        OUTPUT_INT\n    WHILE_END\nWHILE_END\n";
+        assert_eq!(expected, format(source).unwrap());
+        assert_eq!(expected, format(expected).unwrap());
+    }
+
+    #[test]
+    fn arguments() {
+        let source = "RAW_STRING hello\nPRINT \"a # b\"  # comment\nEXPR (1+2)   *  3\nRAW_DATA 1,2, 3";
+        let expected = "RAW_STRING \"hello\"\nPRINT \"a # b\" # comment\nEXPR (1+2) * 3\nRAW_DATA 1, 2, 3\n";
+        assert_eq!(expected, format(source).unwrap());
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(Err((2, "Instruction not found")), format("DUP\nFOO"));
+        assert_eq!(Err((1, "Instruction not found")), format("SUB#x"));
+    }
+
+    /// Pixels of a VASM source without INCLUDE
+    fn pixels(source: &str) -> Vec<Color> {
+        let params = Params::test();
+        let instructions = source.lines().filter_map(|line| Instruction::from_command(line.trim()).unwrap()).collect();
+        assembler::instructions_to_colors(&params, instructions).unwrap()
+    }
+
+    #[test]
+    fn same_pixels() {
+        let source = "RAW_STRING a#b\nPRINT \"x\"#comment\nRAW_STRING x\\\\#y # comment\nRAW_INT 2 # two\nDUP";
+        let formatted = format(source).unwrap();
+        assert_eq!("RAW_STRING \"a#b\"\nPRINT \"x\" #comment\nRAW_STRING \"x\\\\#y\" # comment\nRAW_INT 2 # two\nDUP\n", formatted);
+        assert_eq!(pixels(source), pixels(&formatted));
+    }
+}
@@ -0,0 +1,331 @@
+//! A small structured language compiled into VASM instructions.
+
+use crate::expression::{Compiler, Token, tokenize};
+use crate::instructions::Instruction;
+
+const KEYWORDS: [&str; 10] = ["var", "while", "if", "else", "print", "println", "putc", "read", "readc", "rnd"];
+
+/// Pushes a copy of variable `index`
+fn read_variable(index: usize) -> Vec<Instruction> {
+    let mut instructions = vec![Instruction::Rcycle; index + 1];
+    instructions.push(Instruction::Dup);
+    for _ in 0..=index {
+        instructions.push(Instruction::Swap);
+        instructions.push(Instruction::Cycle);
+    }
+    instructions
+}
+
+/// Pops the top of the stack into variable `index`, the stack must contain only the variables and the value
+fn write_variable(index: usize) -> Vec<Instruction> {
+    let mut instructions: Vec<Instruction> = Vec::new();
+    for _ in 0..index {
+        instructions.push(Instruction::Rcycle);
+        instructions.push(Instruction::Swap);
+    }
+    instructions.push(Instruction::Rcycle);
+    instructions.push(Instruction::Pop);
+    instructions.extend(vec![Instruction::Cycle; index + 1]);
+    instructions
+}
+
+struct Program {
+    tokens: Vec<Token>,
+    lines: Vec<usize>,
+    position: usize,
+    variables: Vec<String>,
+}
+
+impl Program {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Name(name)) if name == keyword)
+    }
+
+    fn skip_separators(&mut self) {
+        while self.peek() == Some(&Token::Separator) {
+            self.position += 1;
+        }
+    }
+
+    fn variable_index(&self, name: &str) -> Result<usize, &'static str> {
+        self.variables.iter().position(|variable| variable == name).ok_or("Unknown variable")
+    }
+
+    fn expression(&mut self) -> Result<Vec<Instruction>, &'static str> {
+        let variables = &self.variables;
+        let variable = |name: &str| variables.iter().position(|variable| variable == name).map(read_variable);
+        let mut compiler = Compiler::new(&self.tokens, self.position, &variable);
+        let instructions = compiler.compile()?;
+        self.position = compiler.position;
+        Ok(instructions)
+    }
+
+    fn name(&mut self) -> Result<String, &'static str> {
+        match self.next() {
+            Some(Token::Name(name)) => Ok(name),
+            _ => Err("Expected a variable name")
+        }
+    }
+
+    fn block(&mut self) -> Result<Vec<Instruction>, &'static str> {
+        if self.next() != Some(Token::BlockOpen) {
+            return Err("Expected {");
+        }
+        let mut instructions: Vec<Instruction> = Vec::new();
+        loop {
+            self.skip_separators();
+            match self.peek() {
+                None => return Err("Missing }"),
+                Some(Token::BlockClose) => {
+                    self.position += 1;
+                    return Ok(instructions);
+                }
+                _ => instructions.extend(self.statement()?)
+            }
+            match self.peek() {
+                Some(Token::Separator) | Some(Token::BlockClose) => {}
+                _ => return Err("Expected the end of the statement")
+            }
+        }
+    }
+
+    fn if_statement(&mut self) -> Result<Vec<Instruction>, &'static str> {
+        let mut instructions = self.expression()?;
+        let then_block = self.block()?;
+        let position = self.position;
+        self.skip_separators();
+        if !self.is_keyword("else") {
+            self.position = position;
+            instructions.extend(vec![Instruction::While, Instruction::Pop]);
+            instructions.extend(then_block);
+            instructions.extend(vec![Instruction::RawInt(0), Instruction::WhileEnd, Instruction::Pop]);
+            return Ok(instructions);
+        }
+        self.position += 1;
+        let else_block = if self.is_keyword("if") {
+            self.position += 1;
+            self.if_statement()?
+        } else {
+            self.block()?
+        };
+        // A flag below the condition tells the second loop whether the first one was skipped
+        instructions.extend(vec![Instruction::RawInt(1), Instruction::Swap, Instruction::While, Instruction::Pop, Instruction::Pop]);
+        instructions.extend(then_block);
+        instructions.extend(vec![Instruction::RawInt(0), Instruction::RawInt(0), Instruction::WhileEnd, Instruction::Pop]);
+        instructions.extend(vec![Instruction::While, Instruction::Pop]);
+        instructions.extend(else_block);
+        instructions.extend(vec![Instruction::RawInt(0), Instruction::WhileEnd, Instruction::Pop]);
+        Ok(instructions)
+    }
+
+    fn statement(&mut self) -> Result<Vec<Instruction>, &'static str> {
+        let keyword = match self.next() {
+            Some(Token::Name(name)) => name,
+            _ => return Err("Expected a statement")
+        };
+        let mut instructions: Vec<Instruction> = Vec::new();
+        match keyword.as_str() {
+            "var" => {
+                let name = self.name()?;
+                let index = self.variable_index(&name)?;
+                if self.peek() == Some(&Token::Assign) {
+                    self.position += 1;
+                    instructions.extend(self.expression()?);
+                    instructions.extend(write_variable(index));
+                }
+            }
+            "while" => {
+                let condition = self.expression()?;
+                let block = self.block()?;
+                instructions.extend(condition.clone());
+                instructions.extend(vec![Instruction::While, Instruction::Pop]);
+                instructions.extend(block);
+                instructions.extend(condition);
+                instructions.extend(vec![Instruction::WhileEnd, Instruction::Pop]);
+            }
+            "if" => instructions.extend(self.if_statement()?),
+            "print" | "println" => {
+                let newline = keyword == "println";
+                match self.peek() {
+                    Some(Token::Str(str)) if newline => instructions.push(Instruction::Println(str.clone())),
+                    Some(Token::Str(str)) => instructions.push(Instruction::Print(str.clone())),
+                    None | Some(Token::Separator) | Some(Token::BlockClose) if newline => {
+                        instructions.extend(vec![Instruction::RawChar('\n'), Instruction::OutputAscii]);
+                        return Ok(instructions);
+                    }
+                    _ => {
+                        instructions.extend(self.expression()?);
+                        instructions.push(Instruction::OutputInt);
+                        if newline {
+                            instructions.extend(vec![Instruction::RawChar('\n'), Instruction::OutputAscii]);
+                        }
+                        return Ok(instructions);
+                    }
+                }
+                self.position += 1;
+            }
+            "putc" => {
+                instructions.extend(self.expression()?);
+                instructions.push(Instruction::OutputAscii);
+            }
+            "read" | "readc" => {
+                let name = self.name()?;
+                let index = self.variable_index(&name)?;
+                instructions.push(if keyword == "read" { Instruction::InputInt } else { Instruction::InputAscii });
+                instructions.extend(write_variable(index));
+            }
+            _ => {
+                let index = self.variable_index(&keyword)?;
+                if self.next() != Some(Token::Assign) {
+                    return Err("Expected =");
+                }
+                instructions.extend(self.expression()?);
+                instructions.extend(write_variable(index));
+            }
+        }
+        Ok(instructions)
+    }
+
+    /// Collects the declared variables, in order of declaration
+    fn declarations(&mut self) -> Result<(), (usize, &'static str)> {
+        for i in 0..self.tokens.len() {
+            if self.tokens[i] != Token::Name("var".to_string()) {
+                continue;
+            }
+            let line = self.lines[i];
+            match self.tokens.get(i + 1) {
+                Some(Token::Name(name)) if KEYWORDS.contains(&name.to_lowercase().as_str()) => return Err((line, "Invalid variable name")),
+                Some(Token::Name(name)) if self.variables.contains(name) => return Err((line, "Variable declared twice")),
+                Some(Token::Name(name)) => self.variables.push(name.clone()),
+                _ => return Err((line, "Expected a variable name"))
+            }
+        }
+        Ok(())
+    }
+
+    fn compile(&mut self) -> Result<Vec<Instruction>, (usize, &'static str)> {
+        self.declarations()?;
+        let mut instructions = vec![Instruction::RawInt(0); self.variables.len()];
+        loop {
+            self.skip_separators();
+            if self.peek().is_none() {
+                return Ok(instructions);
+            }
+            let line = self.lines[self.position];
+            instructions.extend(self.statement().map_err(|err| (self.line(line), err))?);
+            match self.peek() {
+                None | Some(Token::Separator) => {}
+                _ => return Err((self.line(line), "Expected the end of the statement"))
+            }
+        }
+    }
+
+    /// Line of the current token, `default` after the end of the source
+    fn line(&self, default: usize) -> usize {
+        self.lines.get(self.position.min(self.lines.len().saturating_sub(1))).copied().unwrap_or(default)
+    }
+}
+
+/// Compiles a program written in the structured language
+pub fn compile(source: &str) -> Result<Vec<Instruction>, (usize, &'static str)> {
+    let (tokens, lines) = tokenize(source)?;
+    let mut program = Program { tokens, lines, position: 0, variables: Vec::new() };
+    program.compile()
+}
+
+#[cfg(test)]
+mod frontend_tests {
+    use crate::assembler;
+    use crate::color::Color;
+    use crate::error::Error;
+    use crate::instructions::StringEncoding;
+    use crate::interpreter::Interpreter;
+    use crate::params::Params;
+
+    use super::*;
+
+    /// Returns the output and the final stack
+    fn execute(source: &str, input: &str) -> (String, Vec<i32>) {
+        let params = Params::test();
+        let colors: Vec<Color> = compile(source).unwrap().into_iter().flat_map(|instruction| params.get_color(instruction).unwrap()).collect();
+        let mut input = input.as_bytes();
+        let mut output: Vec<u8> = Vec::new();
+        let mut interpreter = Interpreter::new(&colors, &params, &mut input, &mut output).unwrap();
+        interpreter.run().unwrap();
+        let stack: Vec<i32> = interpreter.stack.iter().copied().collect();
+        (String::from_utf8(output).unwrap(), stack)
+    }
+
+    fn run(source: &str, input: &str) -> String {
+        execute(source, input).0
+    }
+
+    #[test]
+    fn variables() {
+        let source = "var a = 1\nvar b = 2\nvar c = 3\nb = a + c * 10\nprint a; print b; print c\nc = b - a; println c";
+        assert_eq!("131330\n", run(source, ""));
+    }
+
+    #[test]
+    fn loops() {
+        let source = "
+            var n
+            var total = 0
+            read n
+            while n {   # sum of 1..n
+                total = total + n
+                n = n - 1
+            }
+            println total
+            print \"bye\"";
+        assert_eq!(("55\nbye".to_string(), vec![0, 55]), execute(source, "10\n"));
+    }
+
+    #[test]
+    fn conditions() {
+        let source = "
+            var i = 6
+            while i {
+                i = i - 1
+                if i % 3 { print i }
+                else if i - 3 { putc 65 + i }
+                else { print \"three\" }
+            }
+            println";
+        assert_eq!("54three21A\n", run(source, ""));
+    }
+
+    #[test]
+    fn read_chars() {
+        let source = "var c\nreadc c\nwhile c { putc c + 1; readc c }";
+        assert_eq!("IBM", run(source, "HAL"));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(1, compile("x = 1").unwrap_err().0);
+        assert_eq!(2, compile("var x\nx = 1 +").unwrap_err().0);
+        assert!(compile("var x\nvar x").is_err());
+        assert!(compile("var while").is_err());
+        assert!(compile("var x\nwhile x { x = 1").is_err());
+        assert!(compile("var x\nx = 1 x = 2").is_err());
+        assert!(compile("print \"a").is_err());
+    }
+
+    #[test]
+    fn unencodable_strings() {
+        let mut params = Params { input_path: "tests/euro.vilmos".to_string(), structured: true, ..Params::test() };
+        params.string_format.encoding = StringEncoding::Latin1;
+        assert!(matches!(assembler::compile_structured(&params), Err(Error::Parse(_))));
+    }
+}
@@ -0,0 +1,153 @@
+//! Self-contained HTML page showing the image of a program next to its source, written with `--html`.
+
+use std::fmt::Write;
+use std::fs;
+
+use crate::assembler;
+use crate::assembler::Program;
+use crate::color::Color;
+use crate::disassembler;
+use crate::error::Error;
+use crate::instructions::Instruction;
+use crate::params::Params;
+use crate::svg::escape;
+
+const STYLE: &str = "\
+body { font-family: sans-serif; margin: 1em; }
+main { display: flex; gap: 2em; align-items: flex-start; }
+#image { display: grid; gap: 0; flex: none; }
+#image div { width: 16px; height: 16px; }
+#image div.active { outline: 2px solid #f0f; outline-offset: -2px; }
+#source { flex: 1; overflow: auto; max-height: 95vh; }
+#source h2 { font-size: 1em; margin: 0.5em 0; }
+#source pre { margin: 0; }
+#source span { display: block; min-height: 1.2em; }
+#source span.active { background: #ff0; }
+";
+
+const SCRIPT: &str = "\
+const cells = document.getElementById('image').children;
+const owner = new Array(cells.length).fill(-1);
+const byLine = {};
+origins.forEach(([file, line, start, end], i) => {
+  for (let p = start; p < end; p++) owner[p] = i;
+  (byLine[file + '-' + line] = byLine[file + '-' + line] || []).push(i);
+});
+let active = [];
+function highlight(list) {
+  active.forEach(element => element.classList.remove('active'));
+  active = [];
+  list.forEach(i => {
+    const [file, line, start, end] = origins[i];
+    for (let p = start; p < end; p++) active.push(cells[p]);
+    const element = document.getElementById('s' + file + '-' + line);
+    if (element) active.push(element);
+  });
+  active.forEach(element => element.classList.add('active'));
+}
+Array.from(cells).forEach((cell, p) => cell.addEventListener('mouseenter', () => {
+  highlight(owner[p] < 0 ? [] : [owner[p]]);
+  const line = active.find(element => element.tagName === 'SPAN');
+  if (line) line.scrollIntoView({block: 'nearest'});
+}));
+document.querySelectorAll('#source span').forEach(span =>
+  span.addEventListener('mouseenter', () => highlight(byLine[span.id.slice(1)] || [])));
+";
+
+/// File shown next to the image
+struct Source {
+    title: String,
+    text: String,
+}
+
+/// Files shown next to the image and the index of the file of every origin
+fn sources(conf: &Params, program: &Program) -> Result<(Vec<Source>, Vec<usize>), Error> {
+    if let Some(vasm) = &program.vasm {
+        let source = Source { title: format!("{} (VASM)", conf.input_path), text: vasm.clone() };
+        return Ok((vec![source], vec![0; program.origins.len()]));
+    }
+    let mut files: Vec<String> = vec![conf.input_path.clone()];
+    let mut indexes: Vec<usize> = Vec::new();
+    for origin in &program.origins {
+        let index = files.iter().position(|file| file == &origin.location.file).unwrap_or_else(|| {
+            files.push(origin.location.file.clone());
+            files.len() - 1
+        });
+        indexes.push(index);
+    }
+    let sources = files.into_iter()
+        .map(|file| match fs::read_to_string(&file) {
+            Ok(text) => Ok(Source { title: file, text }),
+            Err(err) => Err(Error::io(&file, err))
+        })
+        .collect::<Result<Vec<Source>, Error>>()?;
+    Ok((sources, indexes))
+}
+
+pub fn render(conf: &Params, program: &Program) -> Result<String, Error> {
+    let (sources, indexes) = sources(conf, program)?;
+    let (columns, rows) = assembler::layout(conf, program.colors.len());
+    let table = conf.color_table();
+    let quit = conf.opcode_color(Instruction::Quit);
+    let mut html = String::new();
+    writeln!(html, "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>", escape(&conf.input_path)).unwrap();
+    writeln!(html, "<style>\n{}</style>\n</head>\n<body>\n<main>", STYLE).unwrap();
+    writeln!(html, "<div id=\"image\" style=\"grid-template-columns: repeat({}, 16px)\">", columns).unwrap();
+    for i in 0..(columns * rows) as usize {
+        let color: &Color = program.colors.get(i).unwrap_or(&quit);
+        writeln!(html, "<div style=\"background: #{}\" title=\"{}\"></div>",
+                 color.hex(), escape(&disassembler::label(&table, color)).replace('"', "&quot;")).unwrap();
+    }
+    html.push_str("</div>\n<div id=\"source\">\n");
+    for (index, source) in sources.iter().enumerate() {
+        write!(html, "<h2>{}</h2>\n<pre>", escape(&source.title)).unwrap();
+        for (line, text) in source.text.lines().enumerate() {
+            write!(html, "<span id=\"s{}-{}\">{}</span>", index, line + 1, escape(text)).unwrap();
+        }
+        html.push_str("</pre>\n");
+    }
+    html.push_str("</div>\n</main>\n<script>\nconst origins = [");
+    let origins: Vec<String> = program.origins.iter().zip(indexes)
+        .map(|(origin, index)| format!("[{},{},{},{}]", index, origin.location.line, origin.pixels.start, origin.pixels.end))
+        .collect();
+    html.push_str(&origins.join(","));
+    writeln!(html, "];\n{}</script>\n</body>\n</html>", SCRIPT).unwrap();
+    Ok(html)
+}
+
+pub fn write_html(conf: &Params, program: &Program) -> Result<(), Error> {
+    fs::write(&conf.html, render(conf, program)?).map_err(|err| Error::io(&conf.html, err))
+}
+
+#[cfg(test)]
+mod html_tests {
+    use super::*;
+
+    fn params(input: &str) -> Params {
+        let mut params = Params { input_path: input.to_string(), html: "out.html".to_string(), ..Params::test() };
+        params.include_dirs.push("tests/project/lib".to_string());
+        params
+    }
+
+    #[test]
+    fn included_files() {
+        let params = params("tests/project/src/hello.vasm");
+        let program = assembler::parse(&params).unwrap();
+        let html = render(&params, &program).unwrap();
+        assert!(html.contains("<h2>tests/project/src/hello.vasm</h2>"));
+        assert!(html.contains("<h2>tests/project/lib/newline.vasm</h2>"));
+        assert_eq!(program.origins.last().unwrap().pixels.end, program.colors.len());
+        let last = program.origins.last().unwrap();
+        assert!(html.contains(&format!("[1,{},{},{}]];", last.location.line, last.pixels.start, last.pixels.end)));
+        assert!(!html.contains("http://") && !html.contains("https://"));
+    }
+
+    #[test]
+    fn translated_programs() {
+        let params = params("count.bf");
+        let program = Program::from_instructions(&params, vec![Instruction::RawInt(300), Instruction::Dup]).unwrap();
+        let html = render(&params, &program).unwrap();
+        assert!(html.contains("<h2>count.bf (VASM)</h2>\n<pre><span id=\"s0-1\">RAW_INT 300</span><span id=\"s0-2\">DUP</span></pre>"));
+        assert!(html.contains(&format!("const origins = [[0,1,0,{0}],[0,2,{0},{1}]];", program.colors.len() - 1, program.colors.len())));
+    }
+}
@@ -0,0 +1,488 @@
+//! PNG encoding of the assembled images.
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufWriter, Write};
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use strum_macros::{Display, EnumString};
+
+use crate::assembler;
+use crate::color;
+use crate::color::Color;
+use crate::error::Error;
+use crate::instructions::Instruction;
+use crate::metadata;
+use crate::params::Params;
+
+const MAX_PALETTE_COLORS: usize = 256;
+
+/// Compression level of the image data
+#[derive(Clone, Copy, Debug, Default, Display, EnumString, Eq, PartialEq)]
+#[strum(serialize_all = "lowercase")]
+pub enum Compression {
+    Fast,
+    #[default]
+    Default,
+    Best,
+    /// Only Huffman coding, without matching repeated bytes
+    Huffman,
+    /// Only repetitions of the previous byte
+    Rle,
+}
+
+impl From<Compression> for png::Compression {
+    fn from(compression: Compression) -> Self {
+        match compression {
+            Compression::Fast => png::Compression::Fast,
+            Compression::Default => png::Compression::Default,
+            Compression::Best => png::Compression::Best,
+            Compression::Huffman => png::Compression::Huffman,
+            Compression::Rle => png::Compression::Rle
+        }
+    }
+}
+
+/// Filter applied to the rows before compressing them
+#[derive(Clone, Copy, Debug, Default, Display, EnumString, Eq, PartialEq)]
+#[strum(serialize_all = "lowercase")]
+pub enum Filter {
+    None,
+    #[default]
+    Sub,
+    Up,
+    Avg,
+    Paeth,
+    /// The filter giving the smallest row, chosen for every row
+    Adaptive,
+}
+
+/// Colors of an indexed image
+#[derive(Default)]
+pub struct IndexedColors {
+    colors: Vec<Color>,
+    indexes: HashMap<Color, u8>,
+}
+
+impl IndexedColors {
+    /// Palette with the colors in order of appearance, `None` when there are more than 256 colors
+    pub fn new<'a>(colors: impl Iterator<Item=&'a Color>) -> Option<Self> {
+        let mut palette = IndexedColors::default();
+        for color in colors {
+            if !palette.insert(*color) {
+                return None;
+            }
+        }
+        Some(palette)
+    }
+
+    /// Adds the color when it's missing, returns false when the palette is full
+    pub fn insert(&mut self, color: Color) -> bool {
+        if self.indexes.contains_key(&color) {
+            return true;
+        }
+        if self.colors.len() == MAX_PALETTE_COLORS {
+            return false;
+        }
+        self.indexes.insert(color, self.colors.len() as u8);
+        self.colors.push(color);
+        true
+    }
+
+    /// Adds the color of the `QUIT` pixels filling the last row of an image with `pixels` pixels
+    pub fn padded(mut self, conf: &Params, pixels: usize) -> Option<Self> {
+        let (pixel_per_row, height) = assembler::layout(conf, pixels);
+        match (pixel_per_row * height) as usize == pixels || self.insert(conf.opcode_color(Instruction::Quit)) {
+            true => Some(self),
+            false => None
+        }
+    }
+
+    /// Bits of the smallest depth that fits every index
+    pub fn bit_depth(&self) -> u8 {
+        match self.colors.len() {
+            0..=2 => 1,
+            3..=4 => 2,
+            5..=16 => 4,
+            _ => 8
+        }
+    }
+
+    /// Appends the indexes of the row, packed from the most significant bit of each byte
+    fn pack<'a>(&self, row: impl Iterator<Item=&'a Color>, buffer: &mut Vec<u8>) {
+        let depth = self.bit_depth();
+        let (mut byte, mut bits) = (0u8, 0u8);
+        for color in row {
+            byte |= self.indexes[color] << (8 - depth - bits);
+            bits += depth;
+            if bits == 8 {
+                buffer.push(byte);
+                (byte, bits) = (0, 0);
+            }
+        }
+        if bits > 0 {
+            buffer.push(byte);
+        }
+    }
+
+    fn plte(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(self.colors.len() * color::COLOR_COMPONENTS);
+        for color in &self.colors {
+            color.write_data(&mut data);
+        }
+        data
+    }
+}
+
+/// Format and size of a written image
+#[derive(Clone, Debug, PartialEq)]
+pub struct PngSummary {
+    /// Colors of the palette, `None` for RGB images
+    pub palette: Option<usize>,
+    pub bit_depth: u8,
+    pub bytes: u64,
+    /// Bytes saved over the same image written as RGB, 0 when the RGB image is kept
+    pub saved: u64,
+}
+
+impl PngSummary {
+    pub fn to_text(&self) -> String {
+        let format = match self.palette {
+            Some(colors) => format!("indexed with {} colors at {} bits per pixel", colors, self.bit_depth),
+            None => "RGB".to_string()
+        };
+        let comparison = match self.palette {
+            Some(_) => format!(", {} bytes smaller than RGB", self.saved),
+            None => String::new()
+        };
+        format!("PNG: {}, {} bytes{}\n", format, self.bytes, comparison)
+    }
+
+    pub fn to_json(&self) -> String {
+        format!("{{\"color_type\":\"{}\",\"palette\":{},\"bit_depth\":{},\"bytes\":{},\"saved\":{}}}",
+                if self.palette.is_some() { "indexed" } else { "rgb" },
+                self.palette.map_or("null".to_string(), |colors| colors.to_string()), self.bit_depth, self.bytes, self.saved)
+    }
+}
+
+/// Writer counting the bytes written through it
+struct Counter<W: Write> {
+    inner: W,
+    bytes: Rc<Cell<u64>>,
+}
+
+impl<W: Write> Write for Counter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.bytes.set(self.bytes.get() + written as u64);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Encoder receiving the pixels one at a time, every row is written as soon as it is complete
+pub struct PngWriter<W: Write + 'static> {
+    stream: png::StreamWriter<'static, Counter<W>>,
+    bytes: Rc<Cell<u64>>,
+    palette: Option<IndexedColors>,
+    pixel_size: usize,
+    pixel_per_row: usize,
+    /// Rows of pixels not written yet
+    rows: u32,
+    quit: Color,
+    row: Vec<Color>,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write + 'static> PngWriter<W> {
+    /// Writes the header and the text chunks of the image of a program with `pixels` pixels
+    pub fn new(conf: &Params, pixels: usize, palette: Option<IndexedColors>, w: W) -> Result<Self, png::EncodingError> {
+        let pixel_size = conf.pixel_size as u32;
+        let (pixel_per_row, height) = assembler::layout(conf, pixels);
+        let bytes = Rc::new(Cell::new(0));
+        let counter = Counter { inner: w, bytes: bytes.clone() };
+        let mut encoder = png::Encoder::new(counter, pixel_per_row * pixel_size, height * pixel_size);
+        match &palette {
+            Some(palette) => {
+                encoder.set_color(png::ColorType::Indexed);
+                encoder.set_depth(png::BitDepth::from_u8(palette.bit_depth()).unwrap());
+                encoder.set_palette(palette.plte());
+            }
+            None => {
+                encoder.set_color(png::ColorType::Rgb);
+                encoder.set_depth(png::BitDepth::Eight);
+            }
+        }
+        encoder.set_compression(conf.compression.into());
+        match conf.filter {
+            Filter::Adaptive => encoder.set_adaptive_filter(png::AdaptiveFilterType::Adaptive),
+            Filter::None => encoder.set_filter(png::FilterType::NoFilter),
+            Filter::Sub => encoder.set_filter(png::FilterType::Sub),
+            Filter::Up => encoder.set_filter(png::FilterType::Up),
+            Filter::Avg => encoder.set_filter(png::FilterType::Avg),
+            Filter::Paeth => encoder.set_filter(png::FilterType::Paeth)
+        }
+        let mut writer = encoder.write_header()?;
+        metadata::write(&mut writer, conf)?;
+        Ok(PngWriter {
+            stream: writer.into_stream_writer()?,
+            bytes,
+            palette,
+            pixel_size: conf.pixel_size as usize,
+            pixel_per_row: pixel_per_row as usize,
+            rows: height,
+            quit: conf.opcode_color(Instruction::Quit),
+            row: Vec::with_capacity(pixel_per_row as usize),
+            buffer: Vec::new(),
+        })
+    }
+
+    /// Appends a pixel to the image
+    pub fn push(&mut self, color: Color) -> Result<(), png::EncodingError> {
+        self.row.push(color);
+        if self.row.len() == self.pixel_per_row {
+            self.write_row()?;
+        }
+        Ok(())
+    }
+
+    fn write_row(&mut self) -> Result<(), png::EncodingError> {
+        let pixels = self.row.iter().flat_map(|color| iter::repeat_n(color, self.pixel_size));
+        match &self.palette {
+            Some(palette) => palette.pack(pixels, &mut self.buffer),
+            None => pixels.for_each(|color| color.write_data(&mut self.buffer))
+        }
+        for _ in 0..self.pixel_size {
+            self.stream.write_all(&self.buffer)?;
+        }
+        self.row.clear();
+        self.buffer.clear();
+        self.rows -= 1;
+        Ok(())
+    }
+
+    /// Fills the missing pixels with `QUIT` pixels and ends the image, an empty program has a single one
+    pub fn finish(mut self) -> Result<PngSummary, png::EncodingError> {
+        while self.rows > 0 {
+            self.row.resize(self.pixel_per_row, self.quit);
+            self.write_row()?;
+        }
+        self.stream.finish()?;
+        Ok(PngSummary {
+            palette: self.palette.as_ref().map(|palette| palette.colors.len()),
+            bit_depth: self.palette.as_ref().map_or(8, IndexedColors::bit_depth),
+            bytes: self.bytes.get(),
+            saved: 0,
+        })
+    }
+}
+
+/// File created next to the output, removed when dropped unless it replaced the output
+struct TempFile {
+    path: PathBuf,
+    persisted: bool,
+}
+
+impl TempFile {
+    fn create(output: &str) -> Result<(Self, File), Error> {
+        static CREATED: AtomicUsize = AtomicUsize::new(0);
+        let dir = Path::new(output).parent().unwrap_or_else(|| Path::new(""));
+        let name = Path::new(output).file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+        loop {
+            let path = dir.join(format!(".{}.{}-{}.tmp", name, process::id(), CREATED.fetch_add(1, Ordering::Relaxed)));
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => return Ok((TempFile { path, persisted: false }, file)),
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(Error::io(output, err))
+            }
+        }
+    }
+
+    /// Moves the file to `output`, replacing it
+    fn persist(mut self, output: &str) -> Result<(), Error> {
+        fs::rename(&self.path, output).map_err(|err| Error::io(output, err))?;
+        self.persisted = true;
+        Ok(())
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Writes the image as RGB and, when the colors fit a palette, as indexed, keeping the smaller file.
+/// Both are temporary files until `finish`, the output is left untouched on errors
+pub struct ImageWriter {
+    output: String,
+    rgb: PngWriter<BufWriter<File>>,
+    indexed: Option<PngWriter<BufWriter<File>>>,
+    // Dropped after the writers, which close the files
+    rgb_file: TempFile,
+    indexed_file: Option<TempFile>,
+}
+
+impl ImageWriter {
+    pub fn create(conf: &Params, pixels: usize, palette: Option<IndexedColors>) -> Result<Self, Error> {
+        let output = &conf.output_path;
+        let writer = |file: File, palette: Option<IndexedColors>| {
+            PngWriter::new(conf, pixels, palette, BufWriter::new(file)).map_err(|err| Error::io(output, err))
+        };
+        let (rgb_file, file) = TempFile::create(output)?;
+        let rgb = writer(file, None)?;
+        let (indexed_file, indexed) = match palette {
+            Some(palette) => {
+                let (indexed_file, file) = TempFile::create(output)?;
+                (Some(indexed_file), Some(writer(file, Some(palette))?))
+            }
+            None => (None, None)
+        };
+        Ok(ImageWriter { output: output.clone(), rgb, indexed, rgb_file, indexed_file })
+    }
+
+    /// Appends a pixel to both images
+    pub fn push(&mut self, color: Color) -> Result<(), Error> {
+        self.rgb.push(color).map_err(|err| Error::io(&self.output, err))?;
+        match &mut self.indexed {
+            Some(indexed) => indexed.push(color).map_err(|err| Error::io(&self.output, err)),
+            None => Ok(())
+        }
+    }
+
+    /// Ends both images and moves the smaller one to the output path
+    pub fn finish(self) -> Result<PngSummary, Error> {
+        let ImageWriter { output, rgb, indexed, rgb_file, indexed_file } = self;
+        let io_error = |err: png::EncodingError| Error::io(&output, err);
+        let rgb = rgb.finish().map_err(io_error)?;
+        let indexed = indexed.map(|indexed| indexed.finish().map_err(io_error)).transpose()?;
+        match (indexed, indexed_file) {
+            (Some(indexed), Some(indexed_file)) if indexed.bytes < rgb.bytes => {
+                indexed_file.persist(&output)?;
+                Ok(PngSummary { saved: rgb.bytes - indexed.bytes, ..indexed })
+            }
+            _ => {
+                rgb_file.persist(&output)?;
+                Ok(rgb)
+            }
+        }
+    }
+}
+
+/// Writes the image to the output, indexed when the `indexed` setting is on, the colors fit a palette
+/// and the indexed image is smaller
+pub fn write_png(conf: &Params, colors: &[Color]) -> Result<PngSummary, Error> {
+    let palette = match conf.indexed {
+        true => IndexedColors::new(colors.iter()).and_then(|palette| palette.padded(conf, colors.len())),
+        false => None
+    };
+    let mut writer = ImageWriter::create(conf, colors.len(), palette)?;
+    for color in colors {
+        writer.push(*color)?;
+    }
+    writer.finish()
+}
+
+#[cfg(test)]
+mod image_tests {
+    use std::env;
+
+    use super::*;
+
+    /// Temporary files left next to `output`
+    fn temp_files(output: &Path) -> usize {
+        let prefix = format!(".{}.", output.file_name().unwrap().to_string_lossy());
+        fs::read_dir(output.parent().unwrap()).unwrap()
+            .filter(|entry| entry.as_ref().unwrap().file_name().to_string_lossy().starts_with(&prefix))
+            .count()
+    }
+
+    #[test]
+    fn bit_depths() {
+        let colors: Vec<Color> = (0..=255).map(|value| Color::new(value, 0, 0)).collect();
+        assert_eq!(1, IndexedColors::new(colors[..2].iter()).unwrap().bit_depth());
+        assert_eq!(2, IndexedColors::new(colors[..3].iter()).unwrap().bit_depth());
+        assert_eq!(4, IndexedColors::new(colors[..16].iter().chain(colors[..4].iter())).unwrap().bit_depth());
+        assert_eq!(8, IndexedColors::new(colors.iter()).unwrap().bit_depth());
+        assert!(IndexedColors::new(colors.iter().chain([Color::new(0, 0, 1)].iter())).is_none());
+        let palette = IndexedColors::new(colors[..3].iter()).unwrap();
+        let mut buffer = Vec::new();
+        palette.pack([colors[2], colors[1], colors[0], colors[2], colors[1]].iter(), &mut buffer);
+        assert_eq!(vec![0b10_01_00_10, 0b01_00_00_00], buffer);
+    }
+
+    #[test]
+    fn indexed_images() {
+        let output = env::temp_dir().join(format!("vilmos_indexed_{}.png", std::process::id()));
+        let mut params = Params {
+            output_path: output.to_string_lossy().to_string(),
+            pixel_size: 20,
+            max_width: 3,
+            ..Params::test()
+        };
+        let colors = assembler::instructions_to_colors(&params, vec![Instruction::Dup, Instruction::Sum,
+                                                                     Instruction::OutputInt, Instruction::Dup]).unwrap();
+        let summary = write_png(&params, &colors).unwrap();
+        assert_eq!(Some(4), summary.palette);
+        assert_eq!(2, summary.bit_depth);
+        assert_eq!(fs::metadata(&output).unwrap().len(), summary.bytes);
+        assert!(summary.saved > 0);
+        assert_eq!(0, temp_files(&output));
+        let decoded = assembler::read_image(&Params { input_path: params.output_path.clone(), pixel_size: 20, ..Default::default() }).unwrap();
+        let quit = params.opcode_color(Instruction::Quit);
+        assert_eq!(colors.iter().chain([quit, quit].iter()).cloned().collect::<Vec<Color>>(), decoded);
+        params.indexed = false;
+        params.filter = Filter::Adaptive;
+        params.compression = Compression::Best;
+        let summary = write_png(&params, &colors).unwrap();
+        assert_eq!((None, 8, 0), (summary.palette, summary.bit_depth, summary.saved));
+        let decoded = assembler::read_image(&Params { input_path: params.output_path.clone(), pixel_size: 20, ..Default::default() }).unwrap();
+        assert_eq!(&colors[..], &decoded[..colors.len()]);
+        // The palette of a tiny image costs more than its pixels, the RGB image is kept
+        params.indexed = true;
+        params.pixel_size = 1;
+        let summary = write_png(&params, &colors).unwrap();
+        assert_eq!((None, 0), (summary.palette, summary.saved));
+        assert_eq!(fs::metadata(&output).unwrap().len(), summary.bytes);
+        assert_eq!(0, temp_files(&output));
+        fs::remove_file(output).unwrap();
+    }
+
+    #[test]
+    fn empty_program() {
+        let output = env::temp_dir().join(format!("vilmos_empty_{}.png", std::process::id()));
+        let params = Params { output_path: output.to_string_lossy().to_string(), ..Params::test() };
+        write_png(&params, &[]).unwrap();
+        let decoded = assembler::read_image(&Params { input_path: params.output_path.clone(), ..Default::default() }).unwrap();
+        assert_eq!(params.get_color(Instruction::Quit).unwrap(), decoded);
+        assert_eq!(0, temp_files(&output));
+        fs::remove_file(output).unwrap();
+    }
+
+    #[test]
+    fn failed_image() {
+        let output = env::temp_dir().join(format!("vilmos_failed_{}.png", std::process::id()));
+        let params = Params { output_path: output.to_string_lossy().to_string(), max_width: 2, ..Params::test() };
+        let mut writer = ImageWriter::create(&params, 4, IndexedColors::new([Color::new(1, 2, 3)].iter())).unwrap();
+        writer.push(Color::new(1, 2, 3)).unwrap();
+        assert_eq!(2, temp_files(&output));
+        drop(writer);
+        assert_eq!(0, temp_files(&output));
+        assert!(!output.exists());
+        let params = Params { output_path: "tests/missing/out.png".to_string(), ..Params::test() };
+        assert!(matches!(write_png(&params, &[]), Err(Error::Io(_))));
+        assert!(!Path::new("tests/missing").exists());
+    }
+}
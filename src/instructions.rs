@@ -1,14 +1,21 @@
+use std::cell::Cell;
 use std::collections::hash_map::Values;
+use std::fs;
+use std::mem;
+use std::path::Path;
 
 use strum::EnumProperty;
 use strum::IntoEnumIterator;
 use strum::VariantNames;
+use strum_macros::Display;
 use strum_macros::EnumIter;
 use strum_macros::EnumProperty;
 use strum_macros::EnumString;
 use strum_macros::EnumVariantNames;
 
 use crate::color::Color;
+use crate::compress;
+use crate::expression;
 use crate::instructions::Instruction::RawString;
 use crate::params::Params;
 use crate::parser;
@@ -19,101 +26,307 @@ const RETRY_RANDOM: u32 = 100;
 const BIT_PER_COLOR: u32 = 9;
 const BIT_MASK: u32 = (1 << BIT_PER_COLOR) - 1;
 
+thread_local! {
+    /// Times `generate_exact_color` fell back to `EASY_NUMBER` and `SUB` on this thread
+    static FALLBACKS: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Fallbacks of `generate_exact_color` on this thread since it started
+pub fn fallbacks() -> usize {
+    FALLBACKS.with(Cell::get)
+}
+
+/// Runs `f` without counting its fallbacks, which are returned instead.
+/// Used for the colors that are only measured or compared, not emitted
+pub fn uncounted<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let before = fallbacks();
+    let result = f();
+    let counted = fallbacks() - before;
+    FALLBACKS.with(|count| count.set(before));
+    (result, counted)
+}
+
+fn count_fallbacks(fallbacks: usize) {
+    FALLBACKS.with(|count| count.set(count.get() + fallbacks));
+}
+
 #[derive(Clone, Debug, EnumIter, EnumString, Hash, Eq, PartialEq, EnumVariantNames, EnumProperty)]
 #[strum(serialize_all = "SCREAMING_SNAKE_CASE")]
 pub enum Instruction {
-    #[strum(props(Color = "2d6a7d"))]
+    #[strum(props(Color = "2d6a7d", Short = "SHL"))]
     Lshift,
-    #[strum(props(Color = "439dba"))]
+    #[strum(props(Color = "439dba", Short = "SHR"))]
     RShift,
-    #[strum(props(Color = "ffffff"))]
+    #[strum(props(Color = "ffffff", Short = "INI"))]
     InputInt,
-    #[strum(props(Color = "000001"))]
+    #[strum(props(Color = "000001", Short = "OUI"))]
     OutputInt,
-    #[strum(props(Color = "00ced1"))]
+    #[strum(props(Color = "00ced1", Short = "SUM"))]
     Sum,
-    #[strum(props(Color = "ffa500"))]
+    #[strum(props(Color = "ffa500", Short = "SUB"))]
     Sub,
-    #[strum(props(Color = "8a2be2"))]
+    #[strum(props(Color = "8a2be2", Short = "DIV"))]
     Div,
-    #[strum(props(Color = "8b0000"))]
+    #[strum(props(Color = "8b0000", Short = "MUL"))]
     Mul,
-    #[strum(props(Color = "ffdab9"))]
+    #[strum(props(Color = "ffdab9", Short = "MOD"))]
     Mod,
-    #[strum(props(Color = "008000"))]
+    #[strum(props(Color = "008000", Short = "RND"))]
     Rnd,
-    #[strum(props(Color = "ecf3dc"))]
+    #[strum(props(Color = "ecf3dc", Short = "AND"))]
     And,
-    #[strum(props(Color = "b7c6e6"))]
+    #[strum(props(Color = "b7c6e6", Short = "OR"))]
     Or,
-    #[strum(props(Color = "f5e3d7"))]
+    #[strum(props(Color = "f5e3d7", Short = "XOR"))]
     Xor,
-    #[strum(props(Color = "e1d3ef"))]
+    #[strum(props(Color = "e1d3ef", Short = "NAN"))]
     Nand,
-    #[strum(props(Color = "ff9aa2"))]
+    #[strum(props(Color = "ff9aa2", Short = "NOT"))]
     Not,
-    #[strum(props(Color = "e3e3e3"))]
+    #[strum(props(Color = "e3e3e3", Short = "INA"))]
     InputAscii,
-    #[strum(props(Color = "4b4b4b"))]
+    #[strum(props(Color = "4b4b4b", Short = "OUA"))]
     OutputAscii,
-    #[strum(props(Color = "cc9e06"))]
+    #[strum(props(Color = "cc9e06", Short = "POP"))]
     Pop,
-    #[strum(props(Color = "ffbd4a"))]
+    #[strum(props(Color = "ffbd4a", Short = "SWP"))]
     Swap,
-    #[strum(props(Color = "e37f9d"))]
+    #[strum(props(Color = "e37f9d", Short = "CYC"))]
     Cycle,
-    #[strum(props(Color = "e994ae"))]
+    #[strum(props(Color = "e994ae", Short = "RCY"))]
     Rcycle,
-    #[strum(props(Color = "006994"))]
+    #[strum(props(Color = "006994", Short = "DUP"))]
     Dup,
-    #[strum(props(Color = "a5a58d"))]
+    #[strum(props(Color = "a5a58d", Short = "REV"))]
     Reverse,
-    #[strum(props(Color = "b7e4c7"))]
+    #[strum(props(Color = "b7e4c7", Short = "QUI"))]
     Quit,
-    #[strum(props(Color = "9B2242"))]
+    #[strum(props(Color = "9B2242", Short = "OUT"))]
     Output,
-    #[strum(props(Color = "2e1a47"))]
+    /// Jumps after the matching `WHILE_END` when the top of the stack is 0 or the stack is empty.
+    /// The top is only peeked, never popped: the generated loops keep their counter or the next
+    /// character on top while they run and pop it after `WHILE_END`
+    #[strum(props(Color = "2e1a47", Short = "WHL"))]
     While,
-    #[strum(props(Color = "68478d"))]
+    #[strum(props(Color = "68478d", Short = "END"))]
     WhileEnd,
-    #[strum(props(Color = "91f68b"))]
+    #[strum(props(Color = "91f68b", Short = "FOP"))]
     FileOpen,
-    #[strum(props(Color = "2fed23"))]
+    #[strum(props(Color = "2fed23", Short = "FCL"))]
     FileClose,
     #[strum(props(Params = "1"))]
     RawString(String),
     #[strum(props(Params = "1"))]
+    RawStringNul(String),
+    #[strum(props(Params = "1"))]
+    RawStringLp(String),
+    #[strum(props(Params = "1"))]
+    RawStringBare(String),
+    #[strum(props(Params = "1"))]
+    RawStringRev(String),
+    #[strum(props(Params = "1"))]
+    Print(String),
+    #[strum(props(Params = "1"))]
+    Println(String),
+    #[strum(props(Params = "1"))]
     RawInt(i32),
+    #[strum(props(Params = "1"))]
+    RawChar(char),
+    #[strum(props(Params = "1", Variadic = "true"))]
+    RawData(Vec<i32>),
+    #[strum(props(Params = "1", Variadic = "true"))]
+    RawDataRev(Vec<i32>),
+    #[strum(props(Params = "1"))]
+    RawDataFile(String),
+    #[strum(props(Params = "1"))]
+    RawBytesFile(String),
+    #[strum(props(Params = "1", Variadic = "true"))]
+    Expr(Vec<Instruction>),
+    #[strum(props(Params = "1"))]
+    Include(String),
     #[strum(props(Params = "3"))]
     RawColor(u8, u8, u8),
 }
 
+/// What is pushed together with the characters of a string
+#[derive(Clone, Copy, Debug, Default, Display, EnumString, Eq, PartialEq)]
+#[strum(serialize_all = "lowercase")]
+pub enum StringTerminator {
+    /// A `'\0'` pushed before the first character
+    #[default]
+    Null,
+    /// The number of characters pushed after the last character, so it is the first value popped
+    Length,
+    /// Only the characters
+    None,
+}
+
+/// How the characters of a string are turned into the values pushed on the stack
+#[derive(Clone, Copy, Debug, Default, Display, EnumString, Eq, PartialEq)]
+#[strum(serialize_all = "lowercase")]
+pub enum StringEncoding {
+    /// The Unicode scalar value of each character
+    #[default]
+    Codepoint,
+    /// The UTF-8 bytes of each character
+    Utf8,
+    /// One byte per character, characters above U+00FF are rejected
+    Latin1,
+}
+
+impl StringEncoding {
+    /// Values to push for `str`, as characters so they can be pushed like the original ones
+    pub fn encode(&self, str: &str) -> Result<Vec<char>, String> {
+        match self {
+            StringEncoding::Codepoint => Ok(str.chars().collect()),
+            StringEncoding::Utf8 => Ok(str.bytes().map(char::from).collect()),
+            StringEncoding::Latin1 => str.chars().map(|c| {
+                if c as u32 <= 0xff {
+                    Ok(c)
+                } else {
+                    Err(format!("The character {:?} can't be encoded in Latin-1", c))
+                }
+            }).collect()
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct StringFormat {
+    pub terminator: StringTerminator,
+    /// Push the characters from the last to the first, leaving the first character on top
+    pub reversed: bool,
+    pub encoding: StringEncoding,
+}
+
 fn char_to_colors(ch: char, conf: &Params) -> Vec<Color> {
     int_to_colors(ch as i32, conf)
 }
 
-fn string_to_colors(str: &str, conf: &Params) -> Vec<Color> {
+/// Pushes `ch` as the difference from `last`, which must be on top of the stack
+fn delta_to_colors(ch: char, last: char, conf: &Params) -> Vec<Color> {
+    let delta = ch as i32 - last as i32;
+    let mut colors = vec![conf.opcode_color(Instruction::Dup)];
+    colors.extend(int_to_colors(delta.abs(), conf));
+    colors.push(conf.opcode_color(if delta > 0 { Instruction::Sum } else { Instruction::Sub }));
+    colors
+}
+
+/// Pushes `ch` knowing that `last_char` is on top of the stack
+pub fn next_char_to_colors(ch: char, last_char: Option<char>, conf: &Params) -> Vec<Color> {
+    if Some(ch) == last_char {
+        return vec![conf.opcode_color(Instruction::Dup)];
+    }
+    match last_char {
+        Some(last) if conf.delta_strings => {
+            let (colors, colors_fallbacks) = uncounted(|| char_to_colors(ch, conf));
+            let (delta, delta_fallbacks) = uncounted(|| delta_to_colors(ch, last, conf));
+            match delta.len() < colors.len() {
+                true => {
+                    count_fallbacks(delta_fallbacks);
+                    delta
+                }
+                false => {
+                    count_fallbacks(colors_fallbacks);
+                    colors
+                }
+            }
+        }
+        _ => char_to_colors(ch, conf)
+    }
+}
+
+pub fn chars_to_colors(chars: &[char], mut last_char: Option<char>, conf: &Params) -> Vec<Color> {
+    let mut colors: Vec<Color> = Vec::new();
+    for &c in chars.iter() {
+        colors.extend(next_char_to_colors(c, last_char, conf));
+        last_char = Some(c);
+    }
+    colors
+}
+
+fn string_to_colors(str: &str, format: StringFormat, conf: &Params) -> Result<Vec<Color>, String> {
+    let mut chars = format.encoding.encode(str)?;
+    if format.reversed {
+        chars.reverse();
+    }
+    let mut colors: Vec<Color> = Vec::new();
+    let mut last_char = None;
+    if format.terminator == StringTerminator::Null {
+        colors.extend(char_to_colors('\0', conf));
+        last_char = Some('\0');
+    }
+    if conf.compress_strings {
+        colors.extend(compress::compress_chars(&chars, last_char, conf));
+    } else {
+        colors.extend(chars_to_colors(&chars, last_char, conf));
+    }
+    if format.terminator == StringTerminator::Length {
+        colors.extend(int_to_colors(chars.len() as i32, conf));
+    }
+    Ok(colors)
+}
+
+/// Pushes the string with its first character on top and prints it.
+/// The loop stops on the terminator, which is popped at the end, see [`Instruction::While`]
+fn print_to_colors(str: &str, conf: &Params) -> Result<Vec<Color>, String> {
+    let format = StringFormat { terminator: StringTerminator::Null, reversed: true, ..conf.string_format };
+    let mut colors = string_to_colors(str, format, conf)?;
+    if conf.bulk_print {
+        colors.push(conf.opcode_color(Instruction::Output));
+    } else {
+        colors.push(conf.opcode_color(Instruction::While));
+        colors.push(conf.opcode_color(Instruction::OutputAscii));
+        colors.push(conf.opcode_color(Instruction::WhileEnd));
+        colors.push(conf.opcode_color(Instruction::Pop));
+    }
+    Ok(colors)
+}
+
+/// Pushes the values in order, the last one ends on top of the stack
+fn data_to_colors(values: &[i32], conf: &Params) -> Vec<Color> {
     let mut colors: Vec<Color> = Vec::new();
-    colors.extend(char_to_colors('\0', conf));
-    let mut last_char = '\0';
-    for c in str.chars() {
-        if c == last_char {
-            colors.extend(conf.get_color(Instruction::Dup));
+    let mut last_value = None;
+    for &value in values.iter() {
+        if Some(value) == last_value {
+            colors.push(conf.opcode_color(Instruction::Dup));
         } else {
-            colors.extend(char_to_colors(c, conf));
-            last_char = c;
+            colors.extend(int_to_colors(value, conf));
+            last_value = Some(value);
         }
     }
     colors
 }
 
+/// Parses integers separated by commas or whitespace
+fn parse_data(text: &str) -> Result<Vec<i32>, String> {
+    text.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|value| !value.is_empty())
+        .map(|value| value.parse::<i32>().map_err(|_| format!("{:?} is not a valid integer", value)))
+        .collect()
+}
+
+/// Reads the values of a data file, relative paths are resolved from the input file directory.
+/// The assembler makes the paths read by included files absolute
+fn read_data_file(path: &str, binary: bool, conf: &Params) -> Result<Vec<i32>, String> {
+    let base = Path::new(&conf.input_path).parent().unwrap_or_else(|| Path::new(""));
+    let full_path = base.join(path);
+    if binary {
+        let bytes = fs::read(&full_path).map_err(|err| format!("Unable to read {}: {}", full_path.display(), err))?;
+        Ok(bytes.into_iter().map(i32::from).collect())
+    } else {
+        let text = fs::read_to_string(&full_path).map_err(|err| format!("Unable to read {}: {}", full_path.display(), err))?;
+        parse_data(&text).map_err(|err| format!("{} in {}", err, full_path.display()))
+    }
+}
+
 fn color_contains(k: Color, values: Values<Instruction, Color>) -> bool {
     for v in values {
         if k.eq(v) {
             return true;
         }
     }
-    return false;
+    false
 }
 
 
@@ -134,16 +347,17 @@ pub fn generate_exact_color(val: i32, conf: &Params) -> Vec<Color> {
             return vec![k];
         }
     };
+    FALLBACKS.with(|count| count.set(count.get() + 1));
     let mut colors:Vec<Color>=Vec::new();
     colors.extend(generate_exact_color(EASY_NUMBER+val,conf));
     colors.extend(generate_exact_color(EASY_NUMBER,conf));
-    colors.extend(conf.get_color(Instruction::Sub));
+    colors.push(conf.opcode_color(Instruction::Sub));
 
     colors
 }
 
 
-fn int_to_colors(val_original: i32, conf: &Params) -> Vec<Color> {
+pub fn int_to_colors(val_original: i32, conf: &Params) -> Vec<Color> {
     let mut colors: Vec<Color> = Vec::new();
     let mut val: u32 = val_original as u32;
     let mut first = true;
@@ -158,12 +372,12 @@ fn int_to_colors(val_original: i32, conf: &Params) -> Vec<Color> {
                 colors.extend(generate_exact_color(bits as i32, conf));
                 if i != 0 {
                     colors.extend(generate_exact_color(i as i32, conf));
-                    colors.extend(conf.get_color(Instruction::Lshift));
+                    colors.push(conf.opcode_color(Instruction::Lshift));
                 }
                 if first {
                     first = false;
                 } else {
-                    colors.extend(conf.get_color(Instruction::Sum));
+                    colors.push(conf.opcode_color(Instruction::Sum));
                 }
             }
             val >>= BIT_PER_COLOR;
@@ -178,11 +392,7 @@ fn int_to_colors(val_original: i32, conf: &Params) -> Vec<Color> {
 
 impl Instruction {
     pub fn find_name(name: &str) -> Option<Instruction> {
-        let index = Instruction::VARIANTS.iter().position(|&r| r == name);
-        if index.is_none() {
-            return None;
-        }
-        let index = index.unwrap();
+        let index = Instruction::VARIANTS.iter().position(|&r| r == name)?;
         let instruction: Instruction = Instruction::iter().nth(index).unwrap();
         Some(instruction)
     }
@@ -192,7 +402,7 @@ impl Instruction {
             return Err("Invalid instruction");
         }
         let tokens = tokens.unwrap();
-        if tokens.len() == 0 {
+        if tokens.is_empty() {
             return Ok(None);
         }
         let name = tokens[0].as_str();
@@ -201,13 +411,32 @@ impl Instruction {
             return Err("Instruction not found");
         }
         let instruction = instruction.unwrap();
-        if tokens.len() - 1 != instruction.get_param_count() as usize {
+        let param_count = instruction.get_param_count() as usize;
+        if tokens.len() - 1 != param_count && !(instruction.is_variadic() && tokens.len() - 1 > param_count) {
             return Err("Wrong number of arguments");
         }
-        return match instruction {
+        match instruction {
             Instruction::RawString(_) => {
                 Ok(Some(RawString(tokens[1].clone())))
             },
+            Instruction::RawStringNul(_) => {
+                Ok(Some(Instruction::RawStringNul(tokens[1].clone())))
+            },
+            Instruction::RawStringLp(_) => {
+                Ok(Some(Instruction::RawStringLp(tokens[1].clone())))
+            },
+            Instruction::RawStringBare(_) => {
+                Ok(Some(Instruction::RawStringBare(tokens[1].clone())))
+            },
+            Instruction::RawStringRev(_) => {
+                Ok(Some(Instruction::RawStringRev(tokens[1].clone())))
+            },
+            Instruction::Print(_) => {
+                Ok(Some(Instruction::Print(tokens[1].clone())))
+            },
+            Instruction::Println(_) => {
+                Ok(Some(Instruction::Println(tokens[1].clone())))
+            },
             Instruction::RawInt(_) => {
                 match tokens[1].parse::<i32>() {
                     Ok(val) => {
@@ -218,6 +447,35 @@ impl Instruction {
                     }
                 }
             },
+            Instruction::RawChar(_) => {
+                let mut chars = tokens[1].chars();
+                match (chars.next(), chars.next()) {
+                    (Some(ch), None) => Ok(Some(Instruction::RawChar(ch))),
+                    _ => Err("The argument is not a single character")
+                }
+            },
+            Instruction::RawData(_) | Instruction::RawDataRev(_) => {
+                let values = parse_data(&tokens[1..].join(" ")).map_err(|_| "The argument is not a valid integer")?;
+                if values.is_empty() {
+                    return Err("Wrong number of arguments");
+                }
+                match instruction {
+                    Instruction::RawData(_) => Ok(Some(Instruction::RawData(values))),
+                    _ => Ok(Some(Instruction::RawDataRev(values)))
+                }
+            },
+            Instruction::Expr(_) => {
+                Ok(Some(Instruction::Expr(expression::compile(&tokens[1..].join(" "))?)))
+            },
+            Instruction::RawDataFile(_) => {
+                Ok(Some(Instruction::RawDataFile(tokens[1].clone())))
+            },
+            Instruction::RawBytesFile(_) => {
+                Ok(Some(Instruction::RawBytesFile(tokens[1].clone())))
+            },
+            Instruction::Include(_) => {
+                Ok(Some(Instruction::Include(tokens[1].clone())))
+            },
             Instruction::RawColor(_, _, _) => {
                 let r = tokens[1].parse::<u8>();
                 let g = tokens[2].parse::<u8>();
@@ -233,17 +491,96 @@ impl Instruction {
 }
 
 impl Instruction {
-    pub fn get_default_colors(&self, conf: &Params) -> Vec<Color> {
-        match self {
-            Instruction::RawString(str) => string_to_colors(&str, conf),
+    /// Pixels of the instruction, fails when a string can't be encoded or a data file can't be read
+    pub fn get_default_colors(&self, conf: &Params) -> Result<Vec<Color>, String> {
+        Ok(match self {
+            Instruction::RawString(str) => string_to_colors(str, conf.string_format, conf)?,
+            Instruction::RawStringNul(str) => {
+                string_to_colors(str, StringFormat { terminator: StringTerminator::Null, ..conf.string_format }, conf)?
+            }
+            Instruction::RawStringLp(str) => {
+                string_to_colors(str, StringFormat { terminator: StringTerminator::Length, ..conf.string_format }, conf)?
+            }
+            Instruction::RawStringBare(str) => {
+                string_to_colors(str, StringFormat { terminator: StringTerminator::None, ..conf.string_format }, conf)?
+            }
+            Instruction::RawStringRev(str) => {
+                string_to_colors(str, StringFormat { reversed: true, ..conf.string_format }, conf)?
+            }
+            Instruction::Print(str) => print_to_colors(str, conf)?,
+            Instruction::Println(str) => print_to_colors(&format!("{}\n", str), conf)?,
             Instruction::RawInt(val) => int_to_colors(*val, conf),
+            Instruction::RawChar(ch) => char_to_colors(*ch, conf),
+            Instruction::RawData(values) => data_to_colors(values, conf),
+            Instruction::RawDataRev(values) => {
+                let values: Vec<i32> = values.iter().rev().copied().collect();
+                data_to_colors(&values, conf)
+            }
+            Instruction::Expr(instructions) => {
+                let colors = instructions.iter().map(|instruction| conf.get_color(instruction.clone()));
+                colors.collect::<Result<Vec<Vec<Color>>, String>>()?.concat()
+            }
+            Instruction::RawDataFile(path) => data_to_colors(&read_data_file(path, false, conf)?, conf),
+            Instruction::RawBytesFile(path) => data_to_colors(&read_data_file(path, true, conf)?, conf),
             Instruction::RawColor(r, g, b) => vec![Color::new(*r, *g, *b)],
-            _ => {
-                let val = u32::from_str_radix(self.get_str("Color").unwrap(), 16);
-                vec![Color::from(val.unwrap())]
+            Instruction::Include(_) => return Err("INCLUDE must be expanded by the assembler".to_string()),
+            _ => vec![self.default_color()]
+        })
+    }
+    /// Color of an instruction without parameters in the reference interpreter
+    pub fn default_color(&self) -> Color {
+        let color = self.get_str("Color").expect("Instruction without a color");
+        Color::from(u32::from_str_radix(color, 16).unwrap())
+    }
+    /// Checks the parts of the instruction that depend on the configuration
+    pub fn check(&self, conf: &Params) -> Result<(), String> {
+        match self {
+            Instruction::RawString(str) | Instruction::RawStringNul(str) | Instruction::RawStringLp(str)
+            | Instruction::RawStringBare(str) | Instruction::RawStringRev(str)
+            | Instruction::Print(str) | Instruction::Println(str) => {
+                conf.string_format.encoding.encode(str).map(|_| ())
+            }
+            Instruction::RawDataFile(path) => read_data_file(path, false, conf).map(|_| ()),
+            Instruction::RawBytesFile(path) => read_data_file(path, true, conf).map(|_| ()),
+            _ => Ok(())
+        }
+    }
+    /// Three letters name of an instruction without parameters
+    pub fn short_name(&self) -> Option<&'static str> {
+        self.get_str("Short")
+    }
+    /// Name of the instruction in VASM
+    pub fn name(&self) -> &'static str {
+        let index = Instruction::iter().position(|i| mem::discriminant(&i) == mem::discriminant(self)).unwrap();
+        Instruction::VARIANTS[index]
+    }
+    /// Source that `from_command` parses back into the same instructions
+    pub fn to_command(&self) -> String {
+        let name = self.name();
+        match self {
+            Instruction::RawString(str) | Instruction::RawStringNul(str) | Instruction::RawStringLp(str)
+            | Instruction::RawStringBare(str) | Instruction::RawStringRev(str)
+            | Instruction::Print(str) | Instruction::Println(str)
+            | Instruction::RawDataFile(str) | Instruction::RawBytesFile(str)
+            | Instruction::Include(str) => format!("{} {}", name, parser::quote(str)),
+            Instruction::RawInt(val) => format!("{} {}", name, val),
+            Instruction::RawChar(ch) => format!("{} {}", name, parser::quote(&ch.to_string())),
+            Instruction::RawData(values) | Instruction::RawDataRev(values) => {
+                let values: Vec<String> = values.iter().map(|val| val.to_string()).collect();
+                format!("{} {}", name, values.join(", "))
             }
+            Instruction::RawColor(r, g, b) => format!("{} {} {} {}", name, r, g, b),
+            Instruction::Expr(instructions) => {
+                let lines: Vec<String> = instructions.iter().map(|instruction| instruction.to_command()).collect();
+                lines.join("\n")
+            }
+            _ => name.to_string()
         }
     }
+    /// Whether the instruction accepts more than `get_param_count` parameters
+    pub fn is_variadic(&self) -> bool {
+        self.get_str("Variadic").is_some()
+    }
     pub fn get_param_count(&self) -> u8 {
         let x: Option<&str> = self.get_str("Params");
         match x {
@@ -251,6 +588,11 @@ impl Instruction {
             Some(val) => val.parse::<u8>().unwrap()
         }
     }
+    /// Whether the instruction is a single pixel with a color of its own, the others are directives
+    /// generating the pixels of their parameters
+    pub fn is_opcode(&self) -> bool {
+        self.get_param_count() == 0
+    }
 }
 
 #[cfg(test)]
@@ -380,26 +722,14 @@ mod instruction_tests {
     }
 
     fn get_default_map() -> Params {
-        Params {
-            custom_colors: Default::default(),
-            pixel_size: 1,
-            input_path: "".to_string(),
-            output_path: "".to_string(),
-            ini_path: None,
-            max_width: 30,
-            is_random: false,
-        }
+        Params { max_width: 30, is_random: false, ..Default::default() }
     }
 
     #[test]
     fn simple_parse() {
         let params = get_default_map();
-        for instruction in Instruction::iter() {
-            match instruction {
-                Instruction::RawInt(_) | Instruction::RawString(_) | Instruction::RawColor(_, _, _) => continue,
-                _ => {}
-            }
-            let inst = instruction.get_default_colors(&params);
+        for instruction in Instruction::iter().filter(Instruction::is_opcode) {
+            let inst = instruction.get_default_colors(&params).unwrap();
             let color = u32::from_str_radix(instruction.get_str("Color").unwrap(), 16).unwrap();
             assert_eq!(vec![Color::from(color)], inst);
         }
@@ -408,11 +738,11 @@ mod instruction_tests {
     #[test]
     fn parse_instant_int() {
         let params = get_default_map();
-        let instr = Instruction::RawInt(0).get_default_colors(&params);
+        let instr = Instruction::RawInt(0).get_default_colors(&params).unwrap();
         assert_eq!(vec![Color::from(0)], instr);
-        let instr = Instruction::RawInt(1).get_default_colors(&params);
+        let instr = Instruction::RawInt(1).get_default_colors(&params).unwrap();
         assert_eq!(vec![Color::from(0x010000)], instr);
-        let instr = Instruction::RawInt(750).get_default_colors(&params);
+        let instr = Instruction::RawInt(750).get_default_colors(&params).unwrap();
         assert_eq!(vec![Color::from(0xfffff0)], instr);
     }
 
@@ -424,10 +754,192 @@ mod instruction_tests {
             Color::not_random('\0' as i32),
             Color::not_random('T' as i32),
             Color::not_random('E' as i32),
-            Instruction::Dup.get_default_colors(&params)[0],
+            Instruction::Dup.get_default_colors(&params).unwrap()[0],
             Color::not_random('S' as i32),
             Color::not_random('T' as i32),
         ];
-        assert_eq!(output, Instruction::RawString(s).get_default_colors(&params));
+        assert_eq!(output, Instruction::RawString(s).get_default_colors(&params).unwrap());
+    }
+
+    #[test]
+    fn parse_string_variants() {
+        assert_eq!(Instruction::RawStringNul("A".to_string()), Instruction::from_command("RAW_STRING_NUL A").unwrap().unwrap());
+        assert_eq!(Instruction::RawStringLp("A".to_string()), Instruction::from_command("RAW_STRING_LP A").unwrap().unwrap());
+        assert_eq!(Instruction::RawStringBare("A".to_string()), Instruction::from_command("RAW_STRING_BARE A").unwrap().unwrap());
+        assert_eq!(Instruction::RawStringRev("A".to_string()), Instruction::from_command("RAW_STRING_REV A").unwrap().unwrap());
+        assert!(Instruction::from_command("RAW_STRING_LP").is_err());
+    }
+
+    #[test]
+    fn string_formats() {
+        let mut params = get_default_map();
+        let s = "ABB".to_string();
+        let a = Color::not_random('A' as i32);
+        let b = Color::not_random('B' as i32);
+        let dup = Instruction::Dup.get_default_colors(&params).unwrap()[0];
+        assert_eq!(vec![a, b, dup, Color::not_random(3)], Instruction::RawStringLp(s.clone()).get_default_colors(&params).unwrap());
+        assert_eq!(vec![a, b, dup], Instruction::RawStringBare(s.clone()).get_default_colors(&params).unwrap());
+        assert_eq!(vec![Color::not_random(0), b, dup, a], Instruction::RawStringRev(s.clone()).get_default_colors(&params).unwrap());
+        assert_eq!(vec![Color::not_random(0), a, b, dup], Instruction::RawStringNul(s.clone()).get_default_colors(&params).unwrap());
+
+        params.string_format = StringFormat { terminator: StringTerminator::None, reversed: true, ..Default::default() };
+        assert_eq!(vec![b, dup, a], Instruction::RawString(s.clone()).get_default_colors(&params).unwrap());
+        assert_eq!(vec![Color::not_random(0), b, dup, a], Instruction::RawStringNul(s).get_default_colors(&params).unwrap());
+    }
+
+    #[test]
+    fn bare_string_starting_with_nul_is_not_duplicated() {
+        let params = get_default_map();
+        let colors = Instruction::RawStringBare("\0".to_string()).get_default_colors(&params).unwrap();
+        assert_eq!(vec![Color::not_random(0)], colors);
+    }
+
+    #[test]
+    fn print_loop() {
+        let mut params = get_default_map();
+        assert_eq!(Instruction::Println("Hi".to_string()), Instruction::from_command("PRINTLN \"Hi\"").unwrap().unwrap());
+        let colors = Instruction::Print("Hii".to_string()).get_default_colors(&params).unwrap();
+        let output = vec![
+            Color::not_random(0),
+            Color::not_random('i' as i32),
+            Instruction::Dup.get_default_colors(&params).unwrap()[0],
+            Color::not_random('H' as i32),
+            Instruction::While.get_default_colors(&params).unwrap()[0],
+            Instruction::OutputAscii.get_default_colors(&params).unwrap()[0],
+            Instruction::WhileEnd.get_default_colors(&params).unwrap()[0],
+            Instruction::Pop.get_default_colors(&params).unwrap()[0],
+        ];
+        assert_eq!(output, colors);
+
+        params.bulk_print = true;
+        let colors = Instruction::Println("H".to_string()).get_default_colors(&params).unwrap();
+        let output = vec![
+            Color::not_random(0),
+            Color::not_random('\n' as i32),
+            Color::not_random('H' as i32),
+            Instruction::Output.get_default_colors(&params).unwrap()[0],
+        ];
+        assert_eq!(output, colors);
+    }
+
+    #[test]
+    fn delta_string() {
+        let mut params = get_default_map();
+        let s = "\u{1F600}\u{1F601}\u{1F5FF}A".to_string();
+        let plain = Instruction::RawStringBare(s.clone()).get_default_colors(&params).unwrap();
+        params.delta_strings = true;
+        let delta = Instruction::RawStringBare(s.clone()).get_default_colors(&params).unwrap();
+        let dup = Instruction::Dup.get_default_colors(&params).unwrap()[0];
+        let mut output = int_to_colors(0x1F600, &params);
+        output.extend(vec![dup, Color::not_random(1), Instruction::Sum.get_default_colors(&params).unwrap()[0]]);
+        output.extend(vec![dup, Color::not_random(2), Instruction::Sub.get_default_colors(&params).unwrap()[0]]);
+        output.push(Color::not_random('A' as i32));
+        assert_eq!(output, delta);
+        assert!(delta.len() < plain.len());
+    }
+
+    #[test]
+    fn string_encodings() {
+        let mut params = get_default_map();
+        let s = Instruction::RawStringLp("\u{e8}\u{20ac}".to_string());
+        let mut output = vec![Color::not_random(0xe8)];
+        output.extend(int_to_colors(0x20ac, &params));
+        output.push(Color::not_random(2));
+        assert_eq!(output, s.get_default_colors(&params).unwrap());
+        assert!(s.check(&params).is_ok());
+
+        params.string_format.encoding = StringEncoding::Utf8;
+        let output: Vec<Color> = vec![0xc3, 0xa8, 0xe2, 0x82, 0xac, 5].into_iter().map(Color::not_random).collect();
+        assert_eq!(output, s.get_default_colors(&params).unwrap());
+
+        params.string_format.encoding = StringEncoding::Latin1;
+        assert!(s.check(&params).is_err());
+        assert!(s.get_default_colors(&params).is_err());
+        assert!(Instruction::Print("\u{e8}".to_string()).check(&params).is_ok());
+    }
+
+    #[test]
+    fn raw_char() {
+        let params = get_default_map();
+        assert_eq!(Instruction::RawChar('A'), Instruction::from_command("RAW_CHAR A").unwrap().unwrap());
+        assert_eq!(Instruction::RawChar('\n'), Instruction::from_command("RAW_CHAR \\n").unwrap().unwrap());
+        assert_eq!(Instruction::RawChar(' '), Instruction::from_command("RAW_CHAR \" \"").unwrap().unwrap());
+        assert!(Instruction::from_command("RAW_CHAR AB").is_err());
+        assert!(Instruction::from_command("RAW_CHAR \"\"").is_err());
+        assert!(Instruction::from_command("RAW_CHAR \\K").is_err());
+        assert_eq!(vec![Color::not_random('A' as i32)], Instruction::RawChar('A').get_default_colors(&params).unwrap());
+    }
+
+    #[test]
+    fn raw_data() {
+        let params = get_default_map();
+        assert_eq!(Instruction::RawData(vec![1, 2, 2, -3]), Instruction::from_command("RAW_DATA 1, 2,2 -3").unwrap().unwrap());
+        assert_eq!(Instruction::RawDataRev(vec![7]), Instruction::from_command("RAW_DATA_REV 7").unwrap().unwrap());
+        assert!(Instruction::from_command("RAW_DATA").is_err());
+        assert!(Instruction::from_command("RAW_DATA ,").is_err());
+        assert!(Instruction::from_command("RAW_DATA 1, x").is_err());
+        let dup = Instruction::Dup.get_default_colors(&params).unwrap()[0];
+        let output = vec![Color::not_random(1), Color::not_random(2), dup, Color::not_random(3)];
+        assert_eq!(output, Instruction::RawData(vec![1, 2, 2, 3]).get_default_colors(&params).unwrap());
+        let output = vec![Color::not_random(3), Color::not_random(2), dup, Color::not_random(1)];
+        assert_eq!(output, Instruction::RawDataRev(vec![1, 2, 2, 3]).get_default_colors(&params).unwrap());
+    }
+
+    #[test]
+    fn raw_data_file() {
+        let mut params = get_default_map();
+        let dir = std::env::temp_dir().join(format!("vilmos_data_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("table.txt"), "4, 5\n5").unwrap();
+        fs::write(dir.join("table.bin"), [4u8, 5, 5]).unwrap();
+        params.input_path = dir.join("main.vasm").to_str().unwrap().to_string();
+        let dup = Instruction::Dup.get_default_colors(&params).unwrap()[0];
+        let output = vec![Color::not_random(4), Color::not_random(5), dup];
+        let text = Instruction::from_command("RAW_DATA_FILE table.txt").unwrap().unwrap();
+        assert!(text.check(&params).is_ok());
+        assert_eq!(output, text.get_default_colors(&params).unwrap());
+        let binary = Instruction::from_command("RAW_BYTES_FILE table.bin").unwrap().unwrap();
+        assert_eq!(output, binary.get_default_colors(&params).unwrap());
+        assert!(Instruction::RawDataFile("missing.txt".to_string()).check(&params).is_err());
+        assert!(Instruction::RawDataFile("missing.txt".to_string()).get_default_colors(&params).is_err());
+        assert!(Instruction::Include("missing.vasm".to_string()).get_default_colors(&params).is_err());
+        assert!(Instruction::RawDataFile("table.bin".to_string()).check(&params).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn expr() {
+        let params = get_default_map();
+        let instruction = Instruction::from_command("EXPR (2 + 3) * 4 # comment").unwrap().unwrap();
+        let compiled = vec![Instruction::RawInt(2), Instruction::RawInt(3), Instruction::Sum, Instruction::RawInt(4), Instruction::Mul];
+        assert_eq!(Instruction::Expr(compiled), instruction);
+        let output = vec![
+            Color::not_random(2),
+            Color::not_random(3),
+            Instruction::Sum.get_default_colors(&params).unwrap()[0],
+            Color::not_random(4),
+            Instruction::Mul.get_default_colors(&params).unwrap()[0],
+        ];
+        assert_eq!(output, instruction.get_default_colors(&params).unwrap());
+        assert!(Instruction::from_command("EXPR 2 +").is_err());
+        assert!(Instruction::from_command("EXPR").is_err());
+    }
+
+    #[test]
+    fn to_command_round_trip() {
+        let instructions = vec![
+            Instruction::Sum,
+            Instruction::WhileEnd,
+            Instruction::RawInt(-7),
+            Instruction::RawChar('"'),
+            Instruction::RawString("a \"b\"\n\\\0".to_string()),
+            Instruction::Println("".to_string()),
+            Instruction::RawData(vec![1, -2]),
+            Instruction::RawColor(1, 2, 3),
+        ];
+        for instruction in instructions {
+            assert_eq!(instruction, Instruction::from_command(&instruction.to_command()).unwrap().unwrap());
+        }
+        assert_eq!("RAW_INT 1\nNOT", Instruction::Expr(vec![Instruction::RawInt(1), Instruction::Not]).to_command());
     }
 }
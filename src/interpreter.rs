@@ -0,0 +1,243 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{BufRead, Write};
+
+use rand::Rng;
+
+use crate::color::Color;
+use crate::instructions::Instruction;
+use crate::params::Params;
+
+/// Runs an assembled program one pixel at a time.
+///
+/// The top of the stack is the back of the deque: `CYCLE` moves the top to the bottom and `RCYCLE`
+/// the bottom to the top. Binary operations pop the top `a` and then `b`, pushing `b op a`.
+/// `WHILE` jumps after the matching `WHILE_END` when the stack is empty or its top is 0, without popping it,
+/// and `WHILE_END` jumps back to the matching `WHILE`. `OUTPUT` pops and prints characters until it pops a 0.
+/// Pixels that aren't instructions push the sum of their components
+pub struct Interpreter<'a, R: BufRead, W: Write> {
+    program: Vec<Option<Instruction>>,
+    values: Vec<i32>,
+    jumps: HashMap<usize, usize>,
+    pub stack: VecDeque<i32>,
+    input: &'a mut R,
+    output: &'a mut W,
+}
+
+impl<'a, R: BufRead, W: Write> Interpreter<'a, R, W> {
+    pub fn new(colors: &[Color], conf: &Params, input: &'a mut R, output: &'a mut W) -> Result<Self, String> {
+        let palette = conf.color_table();
+        let program: Vec<Option<Instruction>> = colors.iter().map(|color| palette.get(color).cloned()).collect();
+        let mut jumps: HashMap<usize, usize> = HashMap::new();
+        let mut open: Vec<usize> = Vec::new();
+        for (i, instruction) in program.iter().enumerate() {
+            match instruction {
+                Some(Instruction::While) => open.push(i),
+                Some(Instruction::WhileEnd) => {
+                    let start = open.pop().ok_or(format!("WHILE_END at pixel {} without WHILE", i))?;
+                    jumps.insert(start, i);
+                    jumps.insert(i, start);
+                }
+                _ => {}
+            }
+        }
+        if let Some(start) = open.pop() {
+            return Err(format!("WHILE at pixel {} without WHILE_END", start));
+        }
+        Ok(Interpreter {
+            program,
+            values: colors.iter().map(Color::value).collect(),
+            jumps,
+            stack: VecDeque::new(),
+            input,
+            output,
+        })
+    }
+
+    fn pop(&mut self) -> Result<i32, String> {
+        self.stack.pop_back().ok_or_else(|| "Pop from an empty stack".to_string())
+    }
+
+    fn write(&mut self, str: &str) -> Result<(), String> {
+        self.output.write_all(str.as_bytes()).map_err(|err| err.to_string())
+    }
+
+    fn write_char(&mut self, val: i32) -> Result<(), String> {
+        let ch = char::from_u32(val as u32).ok_or(format!("{} is not a valid character", val))?;
+        self.write(&ch.to_string())
+    }
+
+    fn binary(&mut self, instruction: &Instruction) -> Result<(), String> {
+        let a = self.pop()?;
+        let b = self.pop()?;
+        let result = match instruction {
+            Instruction::Sum => b.wrapping_add(a),
+            Instruction::Sub => b.wrapping_sub(a),
+            Instruction::Mul => b.wrapping_mul(a),
+            Instruction::Div | Instruction::Mod if a == 0 => return Err("Division by zero".to_string()),
+            Instruction::Div => b.wrapping_div(a),
+            Instruction::Mod => b.wrapping_rem(a),
+            Instruction::Lshift => b.wrapping_shl(a as u32),
+            Instruction::RShift => b.wrapping_shr(a as u32),
+            Instruction::And => b & a,
+            Instruction::Or => b | a,
+            Instruction::Xor => b ^ a,
+            _ => !(b & a)
+        };
+        self.stack.push_back(result);
+        Ok(())
+    }
+
+    /// Executes the instruction at `pc`, returning the index of the next one or `None` to stop
+    fn step(&mut self, pc: usize) -> Result<Option<usize>, String> {
+        let instruction = match &self.program[pc] {
+            None => {
+                self.stack.push_back(self.values[pc]);
+                return Ok(Some(pc + 1));
+            }
+            Some(instruction) => instruction.clone()
+        };
+        match instruction {
+            Instruction::Sum | Instruction::Sub | Instruction::Mul | Instruction::Div | Instruction::Mod
+            | Instruction::Lshift | Instruction::RShift | Instruction::And | Instruction::Or
+            | Instruction::Xor | Instruction::Nand => self.binary(&instruction)?,
+            Instruction::Not => {
+                let a = self.pop()?;
+                self.stack.push_back(!a);
+            }
+            Instruction::Rnd => {
+                let a = self.pop()?;
+                self.stack.push_back(if a > 0 { rand::thread_rng().gen_range(0..a) } else { 0 });
+            }
+            Instruction::InputInt => {
+                let mut line = String::new();
+                self.input.read_line(&mut line).map_err(|err| err.to_string())?;
+                let val = line.trim().parse::<i32>().map_err(|_| format!("{:?} is not a valid integer", line.trim()))?;
+                self.stack.push_back(val);
+            }
+            Instruction::InputAscii => {
+                let buffer = self.input.fill_buf().map_err(|err| err.to_string())?;
+                let val = buffer.first().map(|&b| b as i32).unwrap_or(0);
+                if !buffer.is_empty() {
+                    self.input.consume(1);
+                }
+                self.stack.push_back(val);
+            }
+            Instruction::OutputInt => {
+                let a = self.pop()?;
+                self.write(&a.to_string())?;
+            }
+            Instruction::OutputAscii => {
+                let a = self.pop()?;
+                self.write_char(a)?;
+            }
+            Instruction::Output => {
+                while let Some(a) = self.stack.pop_back() {
+                    if a == 0 {
+                        break;
+                    }
+                    self.write_char(a)?;
+                }
+            }
+            Instruction::Pop => {
+                self.pop()?;
+            }
+            Instruction::Swap => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.stack.push_back(a);
+                self.stack.push_back(b);
+            }
+            Instruction::Cycle => {
+                let a = self.pop()?;
+                self.stack.push_front(a);
+            }
+            Instruction::Rcycle => {
+                let a = self.stack.pop_front().ok_or("Pop from an empty stack")?;
+                self.stack.push_back(a);
+            }
+            Instruction::Dup => {
+                let a = *self.stack.back().ok_or("Pop from an empty stack")?;
+                self.stack.push_back(a);
+            }
+            Instruction::Reverse => self.stack.make_contiguous().reverse(),
+            Instruction::Quit => return Ok(None),
+            Instruction::While => {
+                if self.stack.back().copied().unwrap_or(0) == 0 {
+                    return Ok(Some(self.jumps[&pc] + 1));
+                }
+            }
+            Instruction::WhileEnd => return Ok(Some(self.jumps[&pc])),
+            _ => return Err(format!("{:?} is not supported by the interpreter", instruction))
+        }
+        Ok(Some(pc + 1))
+    }
+
+    pub fn run(&mut self) -> Result<(), String> {
+        let mut pc = 0;
+        while pc < self.program.len() {
+            match self.step(pc).map_err(|err| format!("{} at pixel {}", err, pc))? {
+                None => break,
+                Some(next) => pc = next
+            }
+        }
+        self.output.flush().map_err(|err| err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod interpreter_tests {
+
+    use super::*;
+
+    fn run(source: &str, input: &str, params: &Params) -> String {
+        let mut colors: Vec<Color> = Vec::new();
+        for line in source.lines() {
+            if let Some(instruction) = Instruction::from_command(line).unwrap() {
+                colors.extend(params.get_color(instruction).unwrap());
+            }
+        }
+        let mut input = input.as_bytes();
+        let mut output: Vec<u8> = Vec::new();
+        Interpreter::new(&colors, params, &mut input, &mut output).unwrap().run().unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn arithmetic() {
+        let params = Params::test();
+        assert_eq!("7", run("RAW_INT 10\nRAW_INT 3\nSUB\nOUTPUT_INT", "", &params));
+        assert_eq!("177013", run("RAW_INT 177013\nOUTPUT_INT", "", &params));
+        assert_eq!("-5", run("RAW_INT -5\nOUTPUT_INT", "", &params));
+        assert_eq!("42", run("EXPR (50 - 8) * 3 / 3\nOUTPUT_INT", "", &params));
+        assert_eq!("0", run("RAW_INT 0\nOUTPUT_INT", "", &params));
+    }
+
+    #[test]
+    fn strings() {
+        let mut params = Params::test();
+        assert_eq!("Hello\n", run("PRINTLN Hello", "", &params));
+        params.bulk_print = true;
+        assert_eq!("Hello", run("PRINT Hello", "", &params));
+        params.bulk_print = false;
+        params.compress_strings = true;
+        params.delta_strings = true;
+        let text = "ab".repeat(20) + "\u{1F600}\u{1F601}" + &"z".repeat(30);
+        assert_eq!(text, run(&format!("PRINT \"{}\"", text), "", &params));
+    }
+
+    #[test]
+    fn input() {
+        let params = Params::test();
+        assert_eq!("5", run("INPUT_INT\nINPUT_INT\nSUM\nOUTPUT_INT", "2\n3\n", &params));
+        assert_eq!("B", run("INPUT_ASCII\nRAW_INT 1\nSUM\nOUTPUT_ASCII", "A", &params));
+    }
+
+    #[test]
+    fn unbalanced_while() {
+        let params = Params::test();
+        let colors = params.get_color(Instruction::While).unwrap();
+        let mut input: &[u8] = &[];
+        let mut output: Vec<u8> = Vec::new();
+        assert!(Interpreter::new(&colors, &params, &mut input, &mut output).is_err());
+    }
+}
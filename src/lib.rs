@@ -0,0 +1,28 @@
+//! Assembler of the Vilmos programs into images, used by the command line and the examples.
+
+pub mod instructions;
+pub mod color;
+pub mod params;
+pub mod parser;
+pub mod assembler;
+pub mod compress;
+pub mod expression;
+pub mod frontend;
+pub mod brainfuck;
+pub mod config;
+pub mod palette;
+pub mod project;
+pub mod watch;
+pub mod error;
+pub mod interpreter;
+pub mod disassembler;
+pub mod formatter;
+pub mod batch;
+pub mod diagnostics;
+pub mod stats;
+pub mod svg;
+pub mod html;
+pub mod preview;
+pub mod metadata;
+pub mod image;
+pub mod stream;
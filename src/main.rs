@@ -1,56 +1,533 @@
-#[macro_use]
-extern crate ini;
+use std::{env, fs, io, process};
+use std::path::PathBuf;
 
-use argparse::{ArgumentParser, Store, StoreTrue};
+use argparse::{ArgumentParser, Collect, List, Store, StoreOption, StoreTrue};
 
-mod instructions;
-mod color;
-mod params;
-mod parser;
-mod assembler;
+use vilmos_assembler::{assembler, batch, color, config, diagnostics, disassembler, error, formatter, interpreter, metadata, palette, preview, project, stats, watch};
+use vilmos_assembler::error::Error;
+use vilmos_assembler::params::Params;
 
-fn main() {
-    let mut ini_path = String::new();
-    let mut in_path: String = String::new();
-    let mut out_path: String = String::new();
-    let mut pixel_size: u16 = 1;
-    let mut max_width: i16 = -1;
-    let mut disable_random: bool = false;
+const COMMANDS: &str = "assemble, disasm, run, check, fmt, palette, build, from-bf";
 
-    {  // this block limits scope of borrows by ap.refer() method
-        let mut ap = ArgumentParser::new();
-        ap.set_description("Vilmos assembler");
-        ap.refer(&mut in_path)
-            .add_option(&["--input", "-i"], Store,
-                        "Input VASM files").required();
-        ap.refer(&mut out_path)
-            .add_option(&["--output", "-o"], Store,
-                        "Output PNG files").required();
-        ap.refer(&mut ini_path)
+const MESSAGE_FORMAT_HELP: &str = "Format of the errors, warnings and summaries [human, json]";
+
+/// Options accepted by every command
+#[derive(Default)]
+struct GlobalOptions {
+    config: String,
+    print_config: bool,
+    palette: Option<String>,
+    pixel_size: Option<String>,
+    message_format: Option<String>,
+}
+
+impl GlobalOptions {
+    fn register<'a>(&'a mut self, ap: &mut ArgumentParser<'a>) {
+        let GlobalOptions { config, print_config, palette, pixel_size, message_format } = self;
+        ap.refer(config)
             .add_option(&["--config"], Store,
-                        "Config file for custom colors");
-        ap.refer(&mut pixel_size)
-            .add_option(&["--pixel-size"], Store,
+                        "Config file with the [assembler] settings and the [colors] of the instructions");
+        ap.refer(print_config)
+            .add_option(&["--print-config"], StoreTrue,
+                        "Print the settings in use and where they come from, then exit");
+        ap.refer(palette)
+            .add_option(&["--palette"], StoreOption,
+                        "Built-in palette or palette file with the colors of the instructions, overwritten by the config file [see palette list]");
+        ap.refer(pixel_size)
+            .add_option(&["--pixel-size"], StoreOption,
                         "Size of each pixel");
-        ap.refer(&mut max_width)
-            .add_option(&["--max-width"], Store,
+        ap.refer(message_format)
+            .add_option(&["--message-format"], StoreOption, MESSAGE_FORMAT_HELP);
+    }
+
+    fn set_message_format(&self) -> Result<(), Error> {
+        diagnostics::set_format(self.message_format.as_deref().unwrap_or("human"))
+    }
+
+    fn settings(&self) -> Vec<(&'static str, Option<String>)> {
+        vec![("palette", self.palette.clone()), ("pixel_size", self.pixel_size.clone())]
+    }
+}
+
+/// Options of the commands that turn a program into pixels
+#[derive(Default)]
+struct EncodingOptions {
+    max_width: Option<String>,
+    disable_random: bool,
+    string_terminator: Option<String>,
+    reverse_strings: bool,
+    string_encoding: Option<String>,
+    bulk_print: bool,
+    compress_strings: bool,
+    delta_strings: bool,
+    structured: bool,
+    stats: bool,
+    labels: bool,
+    grid: bool,
+    legend: bool,
+    html: Option<String>,
+    preview: bool,
+    seed: Option<String>,
+    no_indexed: bool,
+    compression: Option<String>,
+    filter: Option<String>,
+    streaming: bool,
+}
+
+impl EncodingOptions {
+    fn register<'a>(&'a mut self, ap: &mut ArgumentParser<'a>) {
+        let EncodingOptions {
+            max_width, disable_random, string_terminator, reverse_strings, string_encoding,
+            bulk_print, compress_strings, delta_strings, structured, stats, labels, grid, legend, html, preview, seed,
+            no_indexed, compression, filter, streaming
+        } = self;
+        ap.refer(max_width)
+            .add_option(&["--max-width"], StoreOption,
                         "Max pixels per row [-1 for unlimited]");
-        ap.refer(&mut disable_random)
+        ap.refer(disable_random)
             .add_option(&["--no-random", "-r"], StoreTrue,
                         "Disable randomization during generation of raw pixels");
-        ap.parse_args_or_exit();
+        ap.refer(string_terminator)
+            .add_option(&["--string-terminator"], StoreOption,
+                        "Value pushed with RAW_STRING [null, length, none]");
+        ap.refer(reverse_strings)
+            .add_option(&["--reverse-strings"], StoreTrue,
+                        "Push RAW_STRING characters from the last to the first");
+        ap.refer(string_encoding)
+            .add_option(&["--string-encoding"], StoreOption,
+                        "Values pushed for RAW_STRING characters [codepoint, utf8, latin1]");
+        ap.refer(bulk_print)
+            .add_option(&["--bulk-print"], StoreTrue,
+                        "Use a single OUTPUT instead of a loop for PRINT");
+        ap.refer(compress_strings)
+            .add_option(&["--compress-strings"], StoreTrue,
+                        "Push repeated substrings of RAW_STRING with a loop when shorter");
+        ap.refer(delta_strings)
+            .add_option(&["--delta-strings"], StoreTrue,
+                        "Push RAW_STRING characters as the difference from the previous one when shorter");
+        ap.refer(structured)
+            .add_option(&["--structured"], StoreTrue,
+                        "Input is written in the structured language instead of VASM");
+        ap.refer(stats)
+            .add_option(&["--stats"], StoreTrue,
+                        "Print the pixels of every kind of instruction, the expansions and the image size");
+        ap.refer(labels)
+            .add_option(&["--labels"], StoreTrue,
+                        "Write the instruction or the pushed value on every pixel of an SVG output or of the preview");
+        ap.refer(grid)
+            .add_option(&["--grid"], StoreTrue,
+                        "Draw the lines between the pixels of an SVG output");
+        ap.refer(legend)
+            .add_option(&["--legend"], StoreTrue,
+                        "Add the colors of the instructions below an SVG output");
+        ap.refer(html)
+            .add_option(&["--html"], StoreOption,
+                        "Also write a page showing the image next to the source, {stem} and {dir} work like in the output");
+        ap.refer(preview)
+            .add_option(&["--preview"], StoreTrue,
+                        "Print the image in the terminal with 24-bit colors");
+        ap.refer(seed)
+            .add_option(&["--seed"], StoreOption,
+                        "Seed of the random colors, saved in the image [default: a new one for every program]");
+        ap.refer(no_indexed)
+            .add_option(&["--no-indexed"], StoreTrue,
+                        "Always write RGB images, even when the colors fit a palette");
+        ap.refer(compression)
+            .add_option(&["--compression"], StoreOption,
+                        "PNG compression [fast, default, best, huffman, rle]");
+        ap.refer(filter)
+            .add_option(&["--filter"], StoreOption,
+                        "PNG row filter [none, sub, up, avg, paeth, adaptive]");
+        ap.refer(streaming)
+            .add_option(&["--streaming"], StoreTrue,
+                        "Read the input twice to keep only a row of the image in memory, for very large programs");
+    }
+
+    fn settings(&self) -> Vec<(&'static str, Option<String>)> {
+        vec![
+            ("max_width", self.max_width.clone()),
+            ("random", self.disable_random.then(|| "false".to_string())),
+            ("string_terminator", self.string_terminator.clone()),
+            ("reverse_strings", self.reverse_strings.then(|| "true".to_string())),
+            ("string_encoding", self.string_encoding.clone()),
+            ("bulk_print", self.bulk_print.then(|| "true".to_string())),
+            ("compress_strings", self.compress_strings.then(|| "true".to_string())),
+            ("delta_strings", self.delta_strings.then(|| "true".to_string())),
+            ("structured", self.structured.then(|| "true".to_string())),
+            ("stats", self.stats.then(|| "true".to_string())),
+            ("labels", self.labels.then(|| "true".to_string())),
+            ("grid", self.grid.then(|| "true".to_string())),
+            ("legend", self.legend.then(|| "true".to_string())),
+            ("html", self.html.clone()),
+            ("preview", self.preview.then(|| "true".to_string())),
+            ("seed", self.seed.clone()),
+            ("indexed", self.no_indexed.then(|| "false".to_string())),
+            ("compression", self.compression.clone()),
+            ("filter", self.filter.clone()),
+            ("streaming", self.streaming.then(|| "true".to_string())),
+        ]
+    }
+}
+
+/// Parses the arguments of a command, exiting on `--help` and on invalid arguments
+fn parse_args(ap: &ArgumentParser, args: Vec<String>) {
+    if let Err(code) = ap.parse(args, &mut io::stdout(), &mut io::stderr()) {
+        process::exit(code);
     }
+}
+
+/// Only the settings given on the command line
+fn given(settings: Vec<(&'static str, Option<String>)>) -> Vec<(&'static str, String)> {
+    settings.into_iter().filter_map(|(key, value)| value.map(|value| (key, value))).collect()
+}
+
+/// Applies the command line settings and reads the config file, printing the settings with `--print-config`
+fn load_params(global: &GlobalOptions, settings: Vec<(&'static str, Option<String>)>) -> Result<Params, Error> {
+    global.set_message_format()?;
+    let mut conf = Params { ini_path: Some(global.config.clone()), ..Default::default() };
+    for (key, value) in given(global.settings().into_iter().chain(settings).collect()) {
+        conf.set(key, &config::Value::Str(value), "command line").map_err(Error::Usage)?;
+    }
+    conf.read_config().map_err(Error::Config)?;
+    if global.print_config {
+        conf.print_config();
+        process::exit(0);
+    }
+    Ok(conf)
+}
 
-    let mut conf = params::Params {
-        custom_colors: Default::default(),
-        max_width,
-        pixel_size,
-        input_path: in_path,
-        output_path: out_path,
-        ini_path: Option::from(ini_path.clone()),
-        is_random: !disable_random
+/// Checks that the input, and the output when `output` is set, are given on the command line or in the config
+fn require_input(conf: &Params, output: bool) -> Result<(), Error> {
+    if conf.input_path.is_empty() {
+        return Err(Error::Usage("--input is required".to_string()));
+    }
+    if output && conf.output_path.is_empty() {
+        return Err(Error::Usage("--output is required".to_string()));
+    }
+    Ok(())
+}
+
+fn assemble(args: Vec<String>) -> Result<(), Error> {
+    let mut global = GlobalOptions::default();
+    let mut encoding = EncodingOptions::default();
+    let mut inputs: Vec<String> = Vec::new();
+    let mut out_path: Option<String> = None;
+    let mut emit_vasm = false;
+    let mut watch = false;
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Assembles VASM or structured programs into images");
+        ap.refer(&mut inputs)
+            .add_option(&["--input", "-i"], Collect,
+                        "Input VASM file or glob pattern, can be repeated")
+            .add_argument("inputs", List,
+                          "More inputs");
+        ap.refer(&mut out_path)
+            .add_option(&["--output", "-o"], StoreOption,
+                        "Output PNG or SVG file, or a pattern like out/{stem}.png for many inputs");
+        ap.refer(&mut emit_vasm)
+            .add_option(&["--emit-vasm"], StoreTrue,
+                        "Write the VASM translation of a --structured input instead of the PNG");
+        ap.refer(&mut watch)
+            .add_option(&["--watch"], StoreTrue,
+                        "Assemble again every time the input or one of its included files changes");
+        global.register(&mut ap);
+        encoding.register(&mut ap);
+        parse_args(&ap, args);
+    }
+    let (mut paths, single_input) = batch::expand_inputs(&inputs)?;
+    let mut settings = vec![("input", single_input), ("output", out_path), ("emit_vasm", emit_vasm.then(|| "true".to_string()))];
+    settings.extend(encoding.settings());
+    let conf = load_params(&global, settings)?;
+    if paths.is_empty() {
+        require_input(&conf, true)?;
+        paths.push(PathBuf::from(&conf.input_path));
+    } else if conf.output_path.is_empty() {
+        return Err(Error::Usage("--output is required".to_string()));
+    }
+    if conf.emit_vasm && !conf.structured {
+        return Err(Error::Usage("--emit-vasm requires --structured".to_string()));
+    }
+    let many = paths.len() > 1 || conf.output_path.contains("{stem}") || conf.output_path.contains("{dir}");
+    if many && watch {
+        return Err(Error::Usage("--watch requires a single input".to_string()));
+    }
+    match (many, watch) {
+        (true, _) => batch::assemble_all(&conf, &paths, &conf.output_path),
+        (false, true) => watch::watch(&conf),
+        (false, false) => {
+            let summary = assembler::assemble(&conf)?;
+            diagnostics::summary(&conf.input_path, &conf.output_path, &summary, None);
+            Ok(())
+        }
+    }
+}
+
+fn from_bf(args: Vec<String>) -> Result<(), Error> {
+    let mut global = GlobalOptions::default();
+    let mut encoding = EncodingOptions::default();
+    let mut in_path: Option<String> = None;
+    let mut out_path: Option<String> = None;
+    let mut emit_vasm = false;
+    let mut tape_size: Option<String> = None;
+    let mut no_wrap = false;
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Translates a Brainfuck program into an image");
+        ap.refer(&mut in_path)
+            .add_option(&["--input", "-i"], StoreOption,
+                        "Input Brainfuck file");
+        ap.refer(&mut out_path)
+            .add_option(&["--output", "-o"], StoreOption,
+                        "Output PNG or SVG file");
+        ap.refer(&mut emit_vasm)
+            .add_option(&["--emit-vasm"], StoreTrue,
+                        "Write the VASM translation instead of the PNG");
+        ap.refer(&mut tape_size)
+            .add_option(&["--tape-size"], StoreOption,
+                        "Number of Brainfuck cells");
+        ap.refer(&mut no_wrap)
+            .add_option(&["--no-wrap"], StoreTrue,
+                        "Don't wrap Brainfuck cells between 0 and 255");
+        global.register(&mut ap);
+        encoding.register(&mut ap);
+        parse_args(&ap, args);
+    }
+    let mut settings = vec![
+        ("input", in_path),
+        ("output", out_path),
+        ("emit_vasm", emit_vasm.then(|| "true".to_string())),
+        ("tape_size", tape_size),
+        ("wrap", no_wrap.then(|| "false".to_string())),
+    ];
+    settings.extend(encoding.settings());
+    let mut conf = load_params(&global, settings)?;
+    conf.brainfuck = true;
+    require_input(&conf, true)?;
+    let instructions = assembler::translate_brainfuck(&conf)?;
+    let summary = if conf.emit_vasm {
+        assembler::write_vasm(&conf, &instructions)?;
+        assembler::Summary::new(&conf, assembler::instructions_to_colors(&conf, instructions)?.len())
+    } else {
+        assembler::write_program(&conf, assembler::Program::from_instructions(&conf, instructions)?)?
     };
-    conf.read_colors();
-    let colors = assembler::parse(&conf);
-    assembler::write_image(&conf, &colors);
+    diagnostics::summary(&conf.input_path, &conf.output_path, &summary, None);
+    Ok(())
+}
+
+fn build(args: Vec<String>) -> Result<(), Error> {
+    let mut global = GlobalOptions::default();
+    let mut encoding = EncodingOptions::default();
+    let mut manifest = project::MANIFEST.to_string();
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Assembles the targets of a project that changed since the last build");
+        ap.refer(&mut manifest)
+            .add_option(&["--input", "-i"], Store,
+                        "Project manifest [default: vilmos.toml]");
+        global.register(&mut ap);
+        encoding.register(&mut ap);
+        parse_args(&ap, args);
+    }
+    if !global.config.is_empty() || global.print_config {
+        return Err(Error::Usage("The config file of a project is set in its manifest".to_string()));
+    }
+    global.set_message_format()?;
+    let settings = given(global.settings().into_iter().chain(encoding.settings()).collect());
+    project::build(&manifest, &settings)
+}
+
+fn disasm(args: Vec<String>) -> Result<(), Error> {
+    let mut global = GlobalOptions::default();
+    let mut in_path: Option<String> = None;
+    let mut out_path: Option<String> = None;
+    let mut ignore_source = false;
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Translates an image back into VASM");
+        ap.refer(&mut in_path)
+            .add_option(&["--input", "-i"], StoreOption,
+                        "Input PNG file");
+        ap.refer(&mut out_path)
+            .add_option(&["--output", "-o"], StoreOption,
+                        "Output VASM file");
+        ap.refer(&mut ignore_source)
+            .add_option(&["--ignore-source"], StoreTrue,
+                        "Translate the pixels even when the image contains its VASM source");
+        global.register(&mut ap);
+        parse_args(&ap, args);
+    }
+    let conf = load_params(&global, vec![("input", in_path), ("output", out_path)])?;
+    require_input(&conf, true)?;
+    if !ignore_source {
+        if let Some(source) = metadata::vasm_source(&metadata::read(&conf.input_path)?) {
+            return fs::write(&conf.output_path, source).map_err(|err| Error::io(&conf.output_path, err));
+        }
+    }
+    let colors = assembler::read_image(&conf)?;
+    assembler::write_vasm(&conf, &disassembler::disassemble(&colors, &conf))
+}
+
+/// Pixels of an image, or of a program assembled in memory when the input isn't a PNG.
+/// The statistics are collected only for the programs assembled with the `stats` setting
+fn program_colors(conf: &Params) -> Result<(Vec<color::Color>, Option<stats::Stats>), Error> {
+    if conf.input_path.to_lowercase().ends_with(".png") {
+        Ok((assembler::read_image(conf)?, None))
+    } else {
+        assembler::colors(conf).map(|program| (program.colors, program.stats))
+    }
+}
+
+fn run(args: Vec<String>) -> Result<(), Error> {
+    let mut global = GlobalOptions::default();
+    let mut encoding = EncodingOptions::default();
+    let mut in_path: Option<String> = None;
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Runs an image, or a program assembled on the fly, reading from stdin and writing to stdout");
+        ap.refer(&mut in_path)
+            .add_option(&["--input", "-i"], StoreOption,
+                        "Input PNG, VASM or structured file");
+        global.register(&mut ap);
+        encoding.register(&mut ap);
+        parse_args(&ap, args);
+    }
+    let mut settings = vec![("input", in_path)];
+    settings.extend(encoding.settings());
+    let conf = load_params(&global, settings)?;
+    require_input(&conf, false)?;
+    let (colors, _) = program_colors(&conf)?;
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let mut output = io::stdout();
+    let mut interpreter = interpreter::Interpreter::new(&colors, &conf, &mut input, &mut output)
+        .map_err(|err| Error::parse("unbalanced-loop", err))?;
+    interpreter.run().map_err(Error::Runtime)
+}
+
+fn check(args: Vec<String>) -> Result<(), Error> {
+    let mut global = GlobalOptions::default();
+    let mut encoding = EncodingOptions::default();
+    let mut in_path: Option<String> = None;
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Checks that a program assembles and that its loops are balanced, without writing anything");
+        ap.refer(&mut in_path)
+            .add_option(&["--input", "-i"], StoreOption,
+                        "Input PNG, VASM or structured file");
+        global.register(&mut ap);
+        encoding.register(&mut ap);
+        parse_args(&ap, args);
+    }
+    let mut settings = vec![("input", in_path)];
+    settings.extend(encoding.settings());
+    let conf = load_params(&global, settings)?;
+    require_input(&conf, false)?;
+    let (colors, stats) = program_colors(&conf)?;
+    let (mut input, mut output) = (io::empty(), io::sink());
+    interpreter::Interpreter::new(&colors, &conf, &mut input, &mut output)
+        .map_err(|err| Error::parse("unbalanced-loop", err))?;
+    if conf.preview {
+        diagnostics::note(preview::render(&conf, &colors));
+    }
+    let summary = assembler::Summary { stats, ..assembler::Summary::new(&conf, colors.len()) };
+    diagnostics::summary(&conf.input_path, &conf.output_path, &summary, Some(format!("{}: {} pixels", conf.input_path, colors.len())));
+    Ok(())
+}
+
+fn fmt(args: Vec<String>) -> Result<(), Error> {
+    let mut global = GlobalOptions::default();
+    let mut in_path: Option<String> = None;
+    let mut out_path = String::new();
+    let mut check = false;
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Formats a VASM file, in place unless --output is given");
+        ap.refer(&mut in_path)
+            .add_option(&["--input", "-i"], StoreOption,
+                        "Input VASM file");
+        ap.refer(&mut out_path)
+            .add_option(&["--output", "-o"], Store,
+                        "Output VASM file");
+        ap.refer(&mut check)
+            .add_option(&["--check"], StoreTrue,
+                        "Don't write anything, fail if the file isn't formatted");
+        global.register(&mut ap);
+        parse_args(&ap, args);
+    }
+    let conf = load_params(&global, vec![("input", in_path)])?;
+    require_input(&conf, false)?;
+    let in_path = conf.input_path;
+    let source = fs::read_to_string(&in_path).map_err(|err| Error::io(&in_path, err))?;
+    let formatted = formatter::format(&source)
+        .map_err(|(line, err)| Error::instruction(err, error::Location::of_line(&in_path, &source, line)))?;
+    if check {
+        return match formatted == source {
+            true => Ok(()),
+            false => Err(Error::parse("unformatted", format!("{} isn't formatted", in_path)))
+        };
+    }
+    let out_path = if out_path.is_empty() { &in_path } else { &out_path };
+    fs::write(out_path, formatted).map_err(|err| Error::io(out_path, err))
+}
+
+fn palette(args: Vec<String>) -> Result<(), Error> {
+    let mut global = GlobalOptions::default();
+    let mut action = String::from("list");
+    let mut out_path: Option<String> = None;
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Lists the built-in palettes or exports the colors in use as a palette file");
+        ap.refer(&mut action)
+            .add_argument("action", Store,
+                          "What to do [list, export]");
+        ap.refer(&mut out_path)
+            .add_option(&["--output", "-o"], StoreOption,
+                        "Exported config file, the format depends on the extension [default: stdout]");
+        global.register(&mut ap);
+        parse_args(&ap, args);
+    }
+    let conf = load_params(&global, vec![("output", out_path)])?;
+    match action.as_str() {
+        "list" => {
+            print!("{}", palette::list(&conf));
+            Ok(())
+        }
+        "export" => palette::export(&conf),
+        _ => Err(Error::Usage(format!("Unknown palette action {}", action)))
+    }
+}
+
+fn main() {
+    let mut command = String::new();
+    let mut args: Vec<String> = Vec::new();
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Vilmos assembler");
+        ap.refer(&mut command)
+            .add_argument("command", Store,
+                          "Command to run [assemble, disasm, run, check, fmt, palette, build, from-bf]").required();
+        ap.refer(&mut args)
+            .add_argument("arguments", List,
+                          "Arguments of the command, see COMMAND --help");
+        ap.stop_on_first_argument(true);
+        ap.parse_args_or_exit();
+    }
+    let program = env::args().next().unwrap_or_else(|| "vilmos_assembler".to_string());
+    args.insert(0, format!("{} {}", program, command));
+    let result = match command.as_str() {
+        "assemble" => assemble(args),
+        "from-bf" => from_bf(args),
+        "build" => build(args),
+        "disasm" => disasm(args),
+        "run" => run(args),
+        "check" => check(args),
+        "fmt" => fmt(args),
+        "palette" => palette(args),
+        _ => Err(Error::Usage(format!("Unknown command {}, expected one of {}", command, COMMANDS)))
+    };
+    if let Err(err) = result {
+        diagnostics::error(&err);
+        process::exit(err.exit_code());
+    }
 }
@@ -0,0 +1,130 @@
+//! Text chunks that make the PNG images self-describing.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+
+use png::text_metadata::{DECOMPRESSION_LIMIT, ITXtChunk, TEXtChunk, ZTXtChunk};
+
+use crate::assembler;
+use crate::color;
+use crate::error::Error;
+use crate::palette;
+use crate::params::Params;
+
+pub const SOFTWARE: &str = "Software";
+pub const LANGUAGE: &str = "Source language";
+pub const SOURCE: &str = "Source";
+pub const COLORS: &str = "Colors";
+
+/// Settings written as `tEXt` chunks
+fn settings(conf: &Params) -> Vec<(&'static str, String)> {
+    vec![
+        (SOFTWARE, format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))),
+        (LANGUAGE, conf.language().to_string()),
+        ("Palette", conf.palette.clone()),
+        ("Pixel size", conf.pixel_size.to_string()),
+        ("Max width", conf.max_width.to_string()),
+        ("Random", conf.is_random.to_string()),
+        ("Seed", color::current_seed().to_string()),
+    ]
+}
+
+/// Writes the text chunks of the image assembled from the input of `conf`
+pub fn write<W: Write>(writer: &mut png::Writer<W>, conf: &Params) -> Result<(), png::EncodingError> {
+    for (keyword, text) in settings(conf) {
+        writer.write_text_chunk(&TEXtChunk::new(keyword, text))?;
+    }
+    writer.write_text_chunk(&ZTXtChunk::new(COLORS, palette::colors_config(conf, "colors.ini")))?;
+    // Decoders refuse to inflate longer texts, the image couldn't be read back
+    let source = assembler::source(conf).ok().filter(|source| source.len() <= DECOMPRESSION_LIMIT);
+    if let Some(source) = source {
+        let mut chunk = ITXtChunk::new(SOURCE, source);
+        chunk.compressed = true;
+        writer.write_text_chunk(&chunk)?;
+    }
+    Ok(())
+}
+
+/// Text chunks of an image by keyword
+pub fn read(path: &str) -> Result<HashMap<String, String>, Error> {
+    let io_error = |err: png::DecodingError| Error::io(path, err);
+    let decoder = png::Decoder::new(File::open(path).map_err(|err| Error::io(path, err))?);
+    let reader = decoder.read_info().map_err(io_error)?;
+    let info = reader.info();
+    let mut chunks: HashMap<String, String> = HashMap::new();
+    for chunk in &info.uncompressed_latin1_text {
+        chunks.insert(chunk.keyword.clone(), chunk.text.clone());
+    }
+    for chunk in &info.compressed_latin1_text {
+        chunks.insert(chunk.keyword.clone(), chunk.get_text().map_err(io_error)?);
+    }
+    for chunk in &info.utf8_text {
+        chunks.insert(chunk.keyword.clone(), chunk.get_text().map_err(io_error)?);
+    }
+    Ok(chunks)
+}
+
+/// Embedded source, when the image was assembled from VASM
+pub fn vasm_source(chunks: &HashMap<String, String>) -> Option<&String> {
+    match chunks.get(LANGUAGE).map(String::as_str) {
+        Some("vasm") => chunks.get(SOURCE),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod metadata_tests {
+    use std::env;
+    use std::fs;
+
+    use crate::assembler;
+
+    use super::*;
+
+    #[test]
+    fn embedded_source() {
+        let output = env::temp_dir().join(format!("vilmos_metadata_{}.png", std::process::id()));
+        let mut params = Params {
+            input_path: "tests/test1.vasm".to_string(),
+            output_path: output.to_string_lossy().to_string(),
+            seed: Some(42),
+            ..Default::default()
+        };
+        params.read_config().unwrap();
+        let summary = assembler::assemble(&params).unwrap();
+        let chunks = read(&params.output_path).unwrap();
+        let source = fs::read_to_string(&params.input_path).unwrap();
+        assert_eq!(Some(&source), vasm_source(&chunks));
+        assert_eq!("42", chunks["Seed"]);
+        assert_eq!("default", chunks["Palette"]);
+        assert!(chunks[COLORS].starts_with("[colors]\nLSHIFT=#2d6a7d\n"));
+        assert_eq!(format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")), chunks[SOFTWARE]);
+        // The same seed gives the same random colors
+        let colors = assembler::read_image(&Params { input_path: params.output_path.clone(), ..Default::default() }).unwrap();
+        assembler::assemble(&params).unwrap();
+        let again = assembler::read_image(&Params { input_path: params.output_path.clone(), ..Default::default() }).unwrap();
+        assert_eq!(colors, again);
+        assert_eq!(summary.pixels, colors.len());
+        fs::remove_file(output).unwrap();
+    }
+
+    #[test]
+    fn expanded_includes() {
+        let output = env::temp_dir().join(format!("vilmos_metadata_includes_{}.png", std::process::id()));
+        let params = Params {
+            input_path: "tests/data/main.vasm".to_string(),
+            output_path: output.to_string_lossy().to_string(),
+            ..Params::test()
+        };
+        assembler::assemble(&params).unwrap();
+        let source = vasm_source(&read(&params.output_path).unwrap()).unwrap().clone();
+        assert!(!source.contains("INCLUDE"));
+        let expanded = env::temp_dir().join(format!("vilmos_metadata_includes_{}.vasm", std::process::id()));
+        fs::write(&expanded, source).unwrap();
+        let again = Params { input_path: expanded.to_string_lossy().to_string(), ..params.clone() };
+        assert_eq!(assembler::parse(&params).unwrap().colors, assembler::parse(&again).unwrap().colors);
+        fs::remove_file(output).unwrap();
+        fs::remove_file(expanded).unwrap();
+    }
+}
@@ -0,0 +1,166 @@
+//! Color tables of the instructions, built in or read from a file, selected with `--palette`.
+
+use std::fs;
+
+use strum::{EnumProperty, IntoEnumIterator};
+use strum_macros::{Display, EnumIter, EnumProperty, EnumString, IntoStaticStr};
+
+use crate::color::Color;
+use crate::config;
+use crate::error::Error;
+use crate::instructions::Instruction;
+use crate::params::Params;
+
+#[derive(Clone, Copy, Debug, Default, Display, EnumIter, EnumString, IntoStaticStr, EnumProperty, Eq, PartialEq)]
+#[strum(serialize_all = "kebab-case")]
+pub enum Palette {
+    #[default]
+    #[strum(props(Description = "Colors of the reference interpreter"))]
+    Default,
+    #[strum(props(Description = "Saturated hues evenly spaced around the color wheel"))]
+    Hues,
+    #[strum(props(Description = "Shades of gray from white to dark gray"))]
+    Grayscale,
+}
+
+/// Instructions that have a color, in declaration order
+pub fn opcodes() -> impl Iterator<Item=Instruction> {
+    Instruction::iter().filter(Instruction::is_opcode)
+}
+
+/// Converts a hue between 0 and 1 to a color with fixed saturation and value
+fn hue_to_color(hue: f64) -> Color {
+    let (saturation, value) = (0.8, 0.9);
+    let sector = hue * 6.0;
+    let f = sector - sector.floor();
+    let p = value * (1.0 - saturation);
+    let q = value * (1.0 - saturation * f);
+    let t = value * (1.0 - saturation * (1.0 - f));
+    let (r, g, b) = match sector.floor() as u8 % 6 {
+        0 => (value, t, p),
+        1 => (q, value, p),
+        2 => (p, value, t),
+        3 => (p, q, value),
+        4 => (t, p, value),
+        _ => (value, p, q)
+    };
+    let component = |c: f64| (c * 255.0).round() as u8;
+    Color::new(component(r), component(g), component(b))
+}
+
+impl Palette {
+    pub fn name(&self) -> &'static str {
+        self.into()
+    }
+
+    /// Built-in palette called `name`, `None` when `name` is the path of a palette file
+    pub fn builtin(name: &str) -> Option<Palette> {
+        name.trim().parse().ok()
+    }
+
+    pub fn description(&self) -> &'static str {
+        self.get_str("Description").unwrap()
+    }
+
+    /// Color of `instruction`, which must not have parameters
+    pub fn color(&self, instruction: &Instruction) -> Color {
+        let count = opcodes().count();
+        let index = opcodes().position(|i| &i == instruction).expect("Instruction without a color");
+        match self {
+            Palette::Default => instruction.default_color(),
+            Palette::Hues => hue_to_color(index as f64 / count as f64),
+            Palette::Grayscale => {
+                let shade = u8::MAX - (index * (u8::MAX as usize - 32) / (count - 1)) as u8;
+                Color::new(shade, shade, shade)
+            }
+        }
+    }
+}
+
+/// The built-in palettes, one per line, marking the selected one
+pub fn list(conf: &Params) -> String {
+    Palette::iter()
+        .map(|palette| {
+            let marker = if palette.name() == conf.palette.trim() { '*' } else { ' ' };
+            format!("{} {:<10} {}\n", marker, palette.name(), palette.description())
+        })
+        .collect()
+}
+
+/// Config file with the colors in use, in the format of the extension of `path`
+pub fn colors_config(conf: &Params, path: &str) -> String {
+    let colors: Vec<(String, String)> = opcodes()
+        .map(|i| (i.to_command(), format!("#{}", conf.opcode_color(i).hex())))
+        .collect();
+    config::serialize(path, "colors", &colors)
+}
+
+/// Writes the colors in use as a config file to the output path, or to stdout when it's empty.
+/// The format is chosen from the extension like when loading
+pub fn export(conf: &Params) -> Result<(), Error> {
+    let text = colors_config(conf, &conf.output_path);
+    if conf.output_path.is_empty() {
+        print!("{}", text);
+        Ok(())
+    } else {
+        fs::write(&conf.output_path, text).map_err(|err| Error::io(&conf.output_path, err))
+    }
+}
+
+#[cfg(test)]
+mod palette_tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn distinct_colors() {
+        for palette in Palette::iter() {
+            let colors: HashSet<Color> = opcodes().map(|i| palette.color(&i)).collect();
+            assert_eq!(opcodes().count(), colors.len(), "{}", palette.name());
+        }
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(Some(Palette::Grayscale), Palette::builtin("grayscale"));
+        assert_eq!(None, Palette::builtin("tests/palette.toml"));
+        assert_eq!(Color::new(0xe6, 0x2e, 0x2e), Palette::Hues.color(&Instruction::Lshift));
+        assert_eq!(Color::new(0xff, 0xff, 0xff), Palette::Grayscale.color(&Instruction::Lshift));
+    }
+
+    #[test]
+    fn listed_palettes() {
+        let list = list(&Params { palette: "hues".to_string(), ..Default::default() });
+        let lines: Vec<&str> = list.lines().collect();
+        assert_eq!(Palette::iter().count(), lines.len());
+        assert!(lines[0].starts_with("  default "));
+        assert!(lines[1].starts_with("* hues "));
+    }
+
+    #[test]
+    fn selected_palette() {
+        let mut params = Params { palette: "grayscale".to_string(), ..Params::test() };
+        params.read_config().unwrap();
+        assert_eq!(vec![Palette::Grayscale.color(&Instruction::Sum)], params.get_color(Instruction::Sum).unwrap());
+        let mut params = Params { palette: "hues".to_string(), ini_path: Some("tests/conf.ini".to_string()), ..Params::test() };
+        params.read_config().unwrap();
+        assert_eq!(vec![Color::from(0x010000u32)], params.get_color(Instruction::InputInt).unwrap());
+        assert_eq!(vec![Palette::Hues.color(&Instruction::Sum)], params.get_color(Instruction::Sum).unwrap());
+    }
+
+    #[test]
+    fn palette_file() {
+        let mut params = Params { palette: "tests/palette.toml".to_string(), ..Params::test() };
+        params.read_config().unwrap();
+        assert_eq!(vec![Color::new(0xab, 0xcd, 0xef)], params.get_color(Instruction::Sub).unwrap());
+        assert_eq!(Params::test().get_color(Instruction::Sum).unwrap(), params.get_color(Instruction::Sum).unwrap());
+        // The config file overwrites the colors of the palette
+        let mut params = Params { palette: "tests/palette.toml".to_string(), ini_path: Some("tests/conf.ini".to_string()), ..Params::test() };
+        params.read_config().unwrap();
+        assert_eq!(vec![Color::from(0x010000u32)], params.get_color(Instruction::InputInt).unwrap());
+        assert_eq!(vec![Color::new(0xab, 0xcd, 0xef)], params.get_color(Instruction::Sub).unwrap());
+        let mut params = Params { palette: "tests/missing.toml".to_string(), ..Params::test() };
+        assert!(params.read_config().is_err());
+    }
+}
@@ -1,10 +1,27 @@
 use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
 
 use strum::IntoEnumIterator;
 
+use crate::brainfuck;
 use crate::color::Color;
-use crate::instructions::Instruction;
+use crate::config;
+use crate::config::{Entry, Value};
+use crate::diagnostics;
+use crate::image::{Compression, Filter};
+use crate::instructions::{Instruction, StringFormat};
+use crate::palette::Palette;
 
+/// Settings that can be given as CLI options or in the `[assembler]` section of the config file
+pub const SETTINGS: [&str; 27] = [
+    "input", "output", "palette", "pixel_size", "max_width", "random", "string_terminator", "reverse_strings",
+    "string_encoding", "bulk_print", "compress_strings", "delta_strings", "structured", "emit_vasm", "tape_size", "wrap",
+    "stats", "labels", "grid", "legend", "html", "preview", "seed",
+    "indexed", "compression", "filter", "streaming",
+];
+
+#[derive(Clone)]
 pub struct Params {
     pub custom_colors: HashMap<Instruction, Color>,
     pub pixel_size: u16,
@@ -12,53 +29,326 @@ pub struct Params {
     pub output_path: String,
     pub ini_path: Option<String>,
     pub max_width: i16,
-    pub is_random: bool
+    pub is_random: bool,
+    pub string_format: StringFormat,
+    pub bulk_print: bool,
+    pub compress_strings: bool,
+    pub delta_strings: bool,
+    /// Name of a built-in palette, or file with the `[colors]` of the instructions
+    pub palette: String,
+    pub structured: bool,
+    pub emit_vasm: bool,
+    pub tape_size: u32,
+    pub wrap: bool,
+    /// Print the size statistics of the assembled programs
+    pub stats: bool,
+    /// Write the instruction or the value on every pixel of an SVG or of the preview
+    pub labels: bool,
+    /// Draw the lines between the pixels of an SVG
+    pub grid: bool,
+    /// Add the colors of the instructions below an SVG
+    pub legend: bool,
+    /// Page showing the image next to the source, not written when empty
+    pub html: String,
+    /// Print the image in the terminal
+    pub preview: bool,
+    /// Seed of the random colors, a new one is chosen for every program when missing
+    pub seed: Option<u64>,
+    /// The input is a Brainfuck program
+    pub brainfuck: bool,
+    /// Write a PNG with a palette when the image has at most 256 colors
+    pub indexed: bool,
+    pub compression: Compression,
+    pub filter: Filter,
+    /// Assemble in two passes, keeping only a row of the image in memory
+    pub streaming: bool,
+    /// Directories searched for the files included with INCLUDE
+    pub include_dirs: Vec<String>,
+    /// Where each setting that isn't a default comes from
+    pub sources: HashMap<&'static str, String>,
+}
+
+fn parse_setting<T: FromStr>(value: &Value) -> Option<T> {
+    match value {
+        Value::Str(str) => str.trim().parse::<T>().ok(),
+        Value::Int(val) => val.to_string().parse::<T>().ok(),
+        Value::Bool(val) => val.to_string().parse::<T>().ok(),
+        Value::List(_) => None
+    }
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Params {
+            custom_colors: HashMap::new(),
+            pixel_size: 1,
+            input_path: String::new(),
+            output_path: String::new(),
+            ini_path: None,
+            max_width: -1,
+            is_random: true,
+            string_format: StringFormat::default(),
+            bulk_print: false,
+            compress_strings: false,
+            delta_strings: false,
+            palette: Palette::default().name().to_string(),
+            structured: false,
+            emit_vasm: false,
+            tape_size: brainfuck::DEFAULT_TAPE_SIZE,
+            wrap: true,
+            stats: false,
+            labels: false,
+            grid: false,
+            legend: false,
+            html: String::new(),
+            preview: false,
+            seed: None,
+            brainfuck: false,
+            indexed: true,
+            compression: Compression::default(),
+            filter: Filter::default(),
+            streaming: false,
+            include_dirs: Vec::new(),
+            sources: HashMap::new(),
+        }
+    }
 }
 
 impl Params {
-    pub fn get_color(&self, instruction: Instruction) -> Vec<Color> {
+    pub fn get_color(&self, instruction: Instruction) -> Result<Vec<Color>, String> {
         match self.custom_colors.get(&instruction) {
             None => instruction.get_default_colors(self),
-            Some(k) => vec![k.clone()]
+            Some(k) => Ok(vec![*k])
         }
     }
-    pub fn read_colors(&mut self) {
-        for i in Instruction::iter() {
-            match i {
-                Instruction::RawInt(_) | Instruction::RawString(_) => {}
-                _ => { self.custom_colors.insert(i.clone(), i.get_default_colors(self)[0]); }
-            }
+    /// Color of an instruction without parameters
+    pub fn opcode_color(&self, instruction: Instruction) -> Color {
+        self.custom_colors.get(&instruction).copied().unwrap_or_else(|| instruction.default_color())
+    }
+    /// Instruction of every color used by an instruction without parameters
+    pub fn color_table(&self) -> HashMap<Color, Instruction> {
+        Instruction::iter()
+            .filter(Instruction::is_opcode)
+            .map(|i| (self.opcode_color(i.clone()), i))
+            .collect()
+    }
+
+    /// Changes the setting `key` from a CLI option or the config file, `source` is shown by `print_config`
+    pub fn set(&mut self, key: &str, value: &Value, source: &str) -> Result<(), String> {
+        let key = key.trim().to_lowercase().replace('-', "_");
+        let name = *SETTINGS.iter().find(|&&name| name == key).ok_or(format!("Unknown setting {}", key))?;
+        let invalid = || format!("Invalid value {} for {}", value, name);
+        match name {
+            "input" => self.input_path = parse_setting(value).ok_or_else(invalid)?,
+            "output" => self.output_path = parse_setting(value).ok_or_else(invalid)?,
+            "palette" => self.palette = parse_setting(value).ok_or_else(invalid)?,
+            "pixel_size" => self.pixel_size = parse_setting(value).filter(|&size| size > 0).ok_or_else(invalid)?,
+            "max_width" => self.max_width = parse_setting(value).filter(|&width| width > 0 || width == -1).ok_or_else(invalid)?,
+            "random" => self.is_random = parse_setting(value).ok_or_else(invalid)?,
+            "string_terminator" => self.string_format.terminator = parse_setting(value).ok_or_else(invalid)?,
+            "reverse_strings" => self.string_format.reversed = parse_setting(value).ok_or_else(invalid)?,
+            "string_encoding" => self.string_format.encoding = parse_setting(value).ok_or_else(invalid)?,
+            "bulk_print" => self.bulk_print = parse_setting(value).ok_or_else(invalid)?,
+            "compress_strings" => self.compress_strings = parse_setting(value).ok_or_else(invalid)?,
+            "delta_strings" => self.delta_strings = parse_setting(value).ok_or_else(invalid)?,
+            "structured" => self.structured = parse_setting(value).ok_or_else(invalid)?,
+            "emit_vasm" => self.emit_vasm = parse_setting(value).ok_or_else(invalid)?,
+            "tape_size" => self.tape_size = parse_setting(value).ok_or_else(invalid)?,
+            "wrap" => self.wrap = parse_setting(value).ok_or_else(invalid)?,
+            "stats" => self.stats = parse_setting(value).ok_or_else(invalid)?,
+            "labels" => self.labels = parse_setting(value).ok_or_else(invalid)?,
+            "grid" => self.grid = parse_setting(value).ok_or_else(invalid)?,
+            "legend" => self.legend = parse_setting(value).ok_or_else(invalid)?,
+            "html" => self.html = parse_setting(value).ok_or_else(invalid)?,
+            "preview" => self.preview = parse_setting(value).ok_or_else(invalid)?,
+            "seed" => self.seed = Some(parse_setting(value).ok_or_else(invalid)?),
+            "indexed" => self.indexed = parse_setting(value).ok_or_else(invalid)?,
+            "compression" => self.compression = parse_setting(value).ok_or_else(invalid)?,
+            "filter" => self.filter = parse_setting(value).ok_or_else(invalid)?,
+            "streaming" => self.streaming = parse_setting(value).ok_or_else(invalid)?,
+            _ => return Err(format!("Unknown setting {}", name))
         }
-        if self.ini_path.is_none() {
-            return;
+        self.sources.insert(name, source.to_string());
+        Ok(())
+    }
+
+    /// Current value of the setting `name`, as it would be written in the config file
+    pub fn get(&self, name: &str) -> String {
+        match name {
+            "input" => self.input_path.clone(),
+            "output" => self.output_path.clone(),
+            "palette" => self.palette.clone(),
+            "pixel_size" => self.pixel_size.to_string(),
+            "max_width" => self.max_width.to_string(),
+            "random" => self.is_random.to_string(),
+            "string_terminator" => self.string_format.terminator.to_string(),
+            "reverse_strings" => self.string_format.reversed.to_string(),
+            "string_encoding" => self.string_format.encoding.to_string(),
+            "bulk_print" => self.bulk_print.to_string(),
+            "compress_strings" => self.compress_strings.to_string(),
+            "delta_strings" => self.delta_strings.to_string(),
+            "structured" => self.structured.to_string(),
+            "emit_vasm" => self.emit_vasm.to_string(),
+            "tape_size" => self.tape_size.to_string(),
+            "wrap" => self.wrap.to_string(),
+            "stats" => self.stats.to_string(),
+            "labels" => self.labels.to_string(),
+            "grid" => self.grid.to_string(),
+            "legend" => self.legend.to_string(),
+            "html" => self.html.clone(),
+            "preview" => self.preview.to_string(),
+            "seed" => self.seed.map_or("random".to_string(), |seed| seed.to_string()),
+            "indexed" => self.indexed.to_string(),
+            "compression" => self.compression.to_string(),
+            "filter" => self.filter.to_string(),
+            "streaming" => self.streaming.to_string(),
+            _ => panic!("Unknown setting {}", name)
         }
-        let name = self.ini_path.as_ref().unwrap().trim();
-        if name.is_empty() {
-            return;
+    }
+
+    /// Prints every setting with its value and where it comes from
+    pub fn print_config(&self) {
+        if let Some(path) = self.config_path() {
+            println!("{:<18} = {}", "config", path);
         }
-        let map = ini!(name);
-        if map.get("colors").is_none() {
-            return;
+        for name in SETTINGS {
+            let source = self.sources.get(name).map(String::as_str).unwrap_or("default");
+            println!("{:<18} = {:<20} ({})", name, self.get(name), source);
         }
-        let color_section = map.get("colors").unwrap();
-        for i in color_section {
-            if i.1.is_none() || i.1.as_ref().unwrap().is_empty() {
+    }
+
+    /// Language of the input file: vasm, structured or brainfuck
+    pub fn language(&self) -> &'static str {
+        match (self.structured, self.brainfuck) {
+            (true, _) => "structured",
+            (false, true) => "brainfuck",
+            (false, false) => "vasm"
+        }
+    }
+
+    fn config_path(&self) -> Option<String> {
+        match &self.ini_path {
+            Some(name) if !name.trim().is_empty() => Some(name.trim().to_string()),
+            _ => None
+        }
+    }
+
+    /// Reads the config file: the settings of the `[assembler]` section that weren't already set
+    /// from the command line, then the colors of the palette overwritten by the `[colors]` section
+    pub fn read_config(&mut self) -> Result<(), String> {
+        let name = self.config_path();
+        let entries = match &name {
+            Some(name) => config::load(name)?,
+            None => Vec::new()
+        };
+        let name = name.unwrap_or_default();
+        let dir = Path::new(&name).parent().unwrap_or_else(|| Path::new(""));
+        for entry in entries.iter().filter(|entry| entry.section == "assembler") {
+            let location = format!("{}:{}", name, entry.line);
+            let key = entry.key.trim().to_lowercase().replace('-', "_");
+            if self.sources.contains_key(key.as_str()) {
                 continue;
             }
-            let command = i.0.as_str().to_uppercase();
-            if command.starts_with("RAW") {
-                panic!("Can't overwrite the RAW_ instruction")
+            // The paths are relative to the directory of the config file, like in the project manifests
+            let value = match (key.as_str(), &entry.value) {
+                ("palette", Value::Str(palette)) if Palette::builtin(palette).is_some() => entry.value.clone(),
+                ("input" | "output" | "html" | "palette", Value::Str(path)) if !path.trim().is_empty() => {
+                    Value::Str(dir.join(path.trim()).to_string_lossy().to_string())
+                }
+                _ => entry.value.clone()
+            };
+            self.set(&key, &value, &location).map_err(|err| format!("{}: {}", location, err))?;
+        }
+        let builtin = Palette::builtin(&self.palette);
+        for i in Instruction::iter().filter(Instruction::is_opcode) {
+            self.custom_colors.insert(i.clone(), builtin.unwrap_or_default().color(&i));
+        }
+        if builtin.is_none() {
+            let palette = self.palette.clone();
+            self.read_colors(&palette, config::load(&palette)?)?;
+        }
+        self.read_colors(&name, entries.into_iter().filter(|entry| entry.section != "assembler").collect())?;
+        let mut used: HashMap<Color, Instruction> = HashMap::new();
+        for i in Instruction::iter().filter(Instruction::is_opcode) {
+            let color = self.custom_colors[&i];
+            if let Some(other) = used.insert(color, i.clone()) {
+                diagnostics::warning("duplicate-color", &name, format!("{:?} and {:?} have the same color", other, i));
             }
-            let command = Instruction::find_name(command.as_str()).expect("Wrong instruction name in config file");
+        }
+        Ok(())
+    }
 
-            let mut color_str = i.1.clone().unwrap();
-            if color_str.len() == 3 {
-                for i in 0..3 {
-                    color_str.insert(i * 2, color_str.chars().nth(i * 2).unwrap());
-                }
+    /// Overwrites the colors of the instructions with the `[colors]` section of the file `name`
+    fn read_colors(&mut self, name: &str, entries: Vec<Entry>) -> Result<(), String> {
+        let mut empty: Vec<String> = Vec::new();
+        for entry in entries {
+            let location = format!("{}:{}", name, entry.line);
+            if entry.section != "colors" {
+                return Err(format!("{}: Unknown section [{}]", location, entry.section));
+            }
+            let command = entry.key.trim().to_uppercase().replace('-', "_");
+            let instruction = Instruction::find_name(command.as_str())
+                .ok_or(format!("{}: Unknown instruction {}", location, command))?;
+            if !instruction.is_opcode() {
+                return Err(format!("{}: Can't overwrite the color of {}", location, command));
             }
-            let hex_code = u32::from_str_radix(color_str.as_str(), 16).expect("Invalid value for config file");
-            self.custom_colors.insert(command, Color::from(hex_code));
+            if entry.value == Value::Str(String::new()) {
+                empty.push(command);
+                continue;
+            }
+            let color = config::parse_color(&entry.value).map_err(|err| format!("{}: {} for {}", location, err, command))?;
+            self.custom_colors.insert(instruction, color);
+        }
+        if !empty.is_empty() {
+            diagnostics::warning("empty-color", name, format!("Empty colors for {}, the default ones are used", empty.join(", ")));
         }
+        Ok(())
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+impl Params {
+    /// Default settings without random colors and with the default palette read, for the tests
+    pub fn test() -> Params {
+        let mut params = Params { is_random: false, ..Default::default() };
+        params.read_config().unwrap();
+        params
+    }
+}
+
+#[cfg(test)]
+mod params_tests {
+    use crate::instructions::StringTerminator;
+
+    use super::*;
+
+    #[test]
+    fn assembler_section() {
+        let mut params = Params { ini_path: Some("tests/assembler.toml".to_string()), ..Default::default() };
+        params.set("pixel-size", &Value::Str("2".to_string()), "command line").unwrap();
+        params.read_config().unwrap();
+        assert_eq!(2, params.pixel_size);
+        assert_eq!(10, params.max_width);
+        assert!(!params.is_random);
+        assert_eq!("tests/palette.toml", params.palette);
+        assert_eq!(StringTerminator::Length, params.string_format.terminator);
+        assert_eq!(vec![Color::from(0x123456u32)], params.get_color(Instruction::Sum).unwrap());
+        assert_eq!(vec![Color::from(0xabcdefu32)], params.get_color(Instruction::Sub).unwrap());
+        assert_eq!("command line", params.sources["pixel_size"]);
+        assert_eq!("tests/assembler.toml:3", params.sources["max_width"]);
+        assert!(!params.sources.contains_key("tape_size"));
+    }
+
+    #[test]
+    fn invalid_settings() {
+        let mut params = Params::default();
+        assert!(params.set("pixel_size", &Value::Str("big".to_string()), "").is_err());
+        assert!(params.set("pixel_size", &Value::Int(0), "").is_err());
+        assert!(params.set("max_width", &Value::Str("0".to_string()), "").is_err());
+        assert!(params.set("max_width", &Value::Int(-2), "").is_err());
+        params.set("max_width", &Value::Int(-1), "").unwrap();
+        assert!(params.set("palette", &Value::List(Vec::new()), "").is_err());
+        assert!(params.set("colors", &Value::Int(1), "").is_err());
+        params.set("wrap", &Value::Bool(false), "").unwrap();
+        assert_eq!("false", params.get("wrap"));
+    }
+}
@@ -1,8 +1,5 @@
 pub fn escaped(to_escape: Option<char>) -> Option<char> {
-    if to_escape.is_none() {
-        return None;
-    }
-    match to_escape.unwrap() {
+    match to_escape? {
         'n' => Some('\n'),
         'r' => Some('\r'),
         't' => Some('\t'),
@@ -13,6 +10,42 @@ pub fn escaped(to_escape: Option<char>) -> Option<char> {
     }
 }
 
+/// Quotes `str` so that `parse` reads it back as a single token
+pub fn quote(str: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in str.chars() {
+        match c {
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\0' => quoted.push_str("\\0"),
+            _ => quoted.push(c)
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Splits a line into the code and the comment after `#`, if any.
+/// Like `parse`, a `#` starts a comment only where a token could start
+pub fn split_comment(line: &str) -> (&str, Option<&str>) {
+    let (mut token, mut quoted, mut escaped) = (false, false, false);
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => (token, escaped) = (true, true),
+            '"' if quoted => (token, quoted) = (false, false),
+            _ if quoted => {}
+            '"' if !token => quoted = true,
+            '#' if !token => return (&line[..i], Some(&line[i + 1..])),
+            _ => token = !c.is_whitespace()
+        }
+    }
+    (line, None)
+}
+
 fn consume_str(buffer: &mut String, mut actual_char: char) -> Option<String> {
     let quoted = actual_char == '"';
     let mut final_string = String::new();
@@ -26,11 +59,7 @@ fn consume_str(buffer: &mut String, mut actual_char: char) -> Option<String> {
     loop {
         if actual_char == '\\' {// escape \
             let to_escape = buffer.pop();
-            let escaped = escaped(to_escape);
-            if escaped.is_none() {
-                return None
-            }
-            final_string.push(escaped.unwrap());
+            final_string.push(escaped(to_escape)?);
         } else if (quoted && actual_char != '"') || (!quoted && !actual_char.is_whitespace()) { //push other character
             final_string.push(actual_char);
         } else {
@@ -56,5 +85,5 @@ pub fn parse(str: &str) -> Option<Vec<String>> {
         }
         str = str.trim_end().parse().unwrap();
     }
-    return Some(tokens);
+    Some(tokens)
 }
\ No newline at end of file
@@ -0,0 +1,121 @@
+//! Preview of the image in a terminal with 24-bit colors, printed with `--preview`.
+
+use std::env;
+
+use crate::assembler;
+use crate::color::Color;
+use crate::instructions::Instruction;
+use crate::params::Params;
+
+const UPPER_HALF_BLOCK: char = '\u{2580}';
+const RESET: &str = "\x1b[0m";
+const DEFAULT_TERMINAL_WIDTH: u32 = 80;
+/// Characters taken by a pixel with the `labels` setting
+const LABEL_WIDTH: u32 = 4;
+
+/// Columns of the terminal, from `$COLUMNS`
+fn terminal_width() -> u32 {
+    env::var("COLUMNS").ok()
+        .and_then(|columns| columns.trim().parse().ok())
+        .filter(|&columns| columns > 0)
+        .unwrap_or(DEFAULT_TERMINAL_WIDTH)
+}
+
+/// Pixels per row and rows of the preview, the rows fit `terminal_width` characters when `max_width` is -1
+fn layout(conf: &Params, pixels: usize, terminal_width: u32) -> (u32, u32) {
+    if conf.max_width != -1 {
+        return assembler::layout(conf, pixels);
+    }
+    let size = (pixels as u32).max(1);
+    let columns = (terminal_width / if conf.labels { LABEL_WIDTH } else { 1 }).clamp(1, size);
+    (columns, size.div_ceil(columns))
+}
+
+fn foreground(color: &Color) -> String {
+    let (r, g, b) = color.components();
+    format!("\x1b[38;2;{};{};{}m", r, g, b)
+}
+
+fn background(color: &Color) -> String {
+    let (r, g, b) = color.components();
+    format!("\x1b[48;2;{};{};{}m", r, g, b)
+}
+
+pub fn render(conf: &Params, colors: &[Color]) -> String {
+    let (columns, rows) = layout(conf, colors.len(), terminal_width());
+    let quit = conf.opcode_color(Instruction::Quit);
+    let pixel = |x: u32, y: u32| colors.get((y * columns + x) as usize).unwrap_or(&quit);
+    let mut lines: Vec<String> = Vec::new();
+    if conf.labels {
+        let table = conf.color_table();
+        for y in 0..rows {
+            let mut line = String::new();
+            for x in 0..columns {
+                let color = pixel(x, y);
+                let text = match table.get(color).and_then(Instruction::short_name) {
+                    Some(name) => name.to_string(),
+                    None => color.value().to_string()
+                };
+                let text_color = if color.is_dark() { Color::new(255, 255, 255) } else { Color::new(0, 0, 0) };
+                line.push_str(&format!("{}{} {:>3}", background(color), foreground(&text_color), text));
+            }
+            line.push_str(RESET);
+            lines.push(line);
+        }
+        return lines.join("\n");
+    }
+    for y in (0..rows).step_by(2) {
+        let mut line = String::new();
+        for x in 0..columns {
+            line.push_str(&foreground(pixel(x, y)));
+            match y + 1 < rows {
+                true => line.push_str(&background(pixel(x, y + 1))),
+                false => line.push_str("\x1b[49m")
+            }
+            line.push(UPPER_HALF_BLOCK);
+        }
+        line.push_str(RESET);
+        lines.push(line);
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod preview_tests {
+    use super::*;
+
+    fn params() -> Params {
+        Params { max_width: 2, ..Params::test() }
+    }
+
+    #[test]
+    fn half_blocks() {
+        let params = params();
+        let colors = vec![Color::new(1, 2, 3), Color::new(4, 5, 6), Color::new(7, 8, 9)];
+        let quit = params.opcode_color(Instruction::Quit).components();
+        let expected = format!("\x1b[38;2;1;2;3m\x1b[48;2;7;8;9m\u{2580}\x1b[38;2;4;5;6m\x1b[48;2;{};{};{}m\u{2580}\x1b[0m",
+                               quit.0, quit.1, quit.2);
+        assert_eq!(expected, render(&params, &colors));
+        assert_eq!("\x1b[38;2;1;2;3m\x1b[49m\u{2580}\x1b[38;2;4;5;6m\x1b[49m\u{2580}\x1b[0m", render(&params, &colors[..2]));
+    }
+
+    #[test]
+    fn labels() {
+        let mut params = params();
+        params.labels = true;
+        let colors = vec![params.opcode_color(Instruction::OutputInt), Color::new(200, 100, 0)];
+        assert_eq!("\x1b[48;2;0;0;1m\x1b[38;2;255;255;255m OUI\x1b[48;2;200;100;0m\x1b[38;2;255;255;255m 300\x1b[0m", render(&params, &colors));
+    }
+
+    #[test]
+    fn terminal_rows() {
+        let mut params = Params { max_width: -1, ..params() };
+        assert_eq!((80, 2), layout(&params, 100, 80));
+        assert_eq!((30, 1), layout(&params, 30, 80));
+        params.labels = true;
+        assert_eq!((20, 5), layout(&params, 100, 80));
+        assert_eq!((1, 3), layout(&params, 3, 2));
+        params.max_width = 7;
+        assert_eq!((7, 15), layout(&params, 100, 80));
+    }
+}
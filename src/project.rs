@@ -0,0 +1,160 @@
+//! Projects described by a `vilmos.toml` manifest.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::assembler;
+use crate::config;
+use crate::config::{Entry, Value};
+use crate::diagnostics;
+use crate::error::Error;
+use crate::palette::Palette;
+use crate::params::Params;
+
+pub const MANIFEST: &str = "vilmos.toml";
+
+pub struct Target {
+    pub name: String,
+    pub params: Params,
+}
+
+/// Applies a setting of the manifest, making the paths relative to the directory of the manifest
+fn apply(params: &mut Params, dir: &Path, entry: &Entry, location: &str) -> Result<(), String> {
+    let path = |value: &Value| match value {
+        Value::Str(str) => Ok(dir.join(str.trim()).to_string_lossy().to_string()),
+        _ => Err(format!("{}: Expected a path for {}", location, entry.key))
+    };
+    match entry.key.trim().to_lowercase().as_str() {
+        "include" => {
+            let dirs = match &entry.value {
+                Value::List(values) => values.iter().map(path).collect::<Result<Vec<String>, String>>()?,
+                value => vec![path(value)?]
+            };
+            params.include_dirs.extend(dirs);
+        }
+        "config" => params.ini_path = Some(path(&entry.value)?),
+        "palette" if matches!(&entry.value, Value::Str(name) if Palette::builtin(name).is_some()) => {
+            params.set(&entry.key, &entry.value, location).map_err(|err| format!("{}: {}", location, err))?
+        }
+        "input" | "output" | "html" | "palette" => params.set(&entry.key, &Value::Str(path(&entry.value)?), location)
+            .map_err(|err| format!("{}: {}", location, err))?,
+        _ => params.set(&entry.key, &entry.value, location).map_err(|err| format!("{}: {}", location, err))?
+    }
+    Ok(())
+}
+
+/// Reads the targets of the manifest, `overrides` are the settings given on the command line
+pub fn load(manifest: &str, overrides: &[(&str, String)]) -> Result<Vec<Target>, Error> {
+    let dir = Path::new(manifest).parent().unwrap_or_else(|| Path::new("")).to_path_buf();
+    if !Path::new(manifest).is_file() {
+        return Err(Error::io(manifest, "No such file"));
+    }
+    let entries = config::load(manifest).map_err(Error::Config)?;
+    let mut names: Vec<String> = Vec::new();
+    for entry in &entries {
+        match entry.section.strip_prefix("target.") {
+            Some(name) if !names.iter().any(|other| other == name) => names.push(name.to_string()),
+            Some(_) => {}
+            None if entry.section == "project" => {}
+            None => return Err(Error::Config(format!("{}:{}: Unknown section [{}]", manifest, entry.line, entry.section)))
+        }
+    }
+    if names.is_empty() {
+        return Err(Error::Config(format!("{}: No [target.NAME] section", manifest)));
+    }
+    let mut targets: Vec<Target> = Vec::new();
+    for name in names {
+        let mut params = Params::default();
+        let section = format!("target.{}", name);
+        let project = entries.iter().filter(|entry| entry.section == "project");
+        for entry in project.chain(entries.iter().filter(|entry| entry.section == section)) {
+            apply(&mut params, &dir, entry, &format!("{}:{}", manifest, entry.line)).map_err(Error::Config)?;
+        }
+        for (key, value) in overrides {
+            params.set(key, &Value::Str(value.clone()), "command line").map_err(Error::Usage)?;
+        }
+        if params.input_path.is_empty() || params.output_path.is_empty() {
+            return Err(Error::Config(format!("{}: The target {} needs an input and an output", manifest, name)));
+        }
+        params.read_config().map_err(Error::Config)?;
+        targets.push(Target { name, params });
+    }
+    Ok(targets)
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+/// Checks if the output of the target is missing or older than one of the files used to build it
+pub fn is_stale(target: &Target, manifest: &str) -> Result<bool, Error> {
+    let output = match modified(Path::new(&target.params.output_path)) {
+        None => return Ok(true),
+        Some(time) => time
+    };
+    let mut files: Vec<PathBuf> = assembler::dependencies(&target.params)?;
+    files.push(PathBuf::from(manifest));
+    if let Some(path) = &target.params.ini_path {
+        files.push(PathBuf::from(path));
+    }
+    if Palette::builtin(&target.params.palette).is_none() {
+        files.push(PathBuf::from(&target.params.palette));
+    }
+    Ok(files.iter().any(|file| modified(file).is_none_or(|time| time > output)))
+}
+
+/// Assembles the targets of the manifest that changed since the last build
+pub fn build(manifest: &str, overrides: &[(&str, String)]) -> Result<(), Error> {
+    let targets = load(manifest, overrides)?;
+    for target in targets {
+        if !is_stale(&target, manifest)? {
+            diagnostics::note(format!("{} is up to date", target.name));
+            continue;
+        }
+        diagnostics::note(format!("Building {}", target.name));
+        if let Some(dir) = Path::new(&target.params.output_path).parent() {
+            fs::create_dir_all(dir).map_err(|err| Error::io(dir.display(), err))?;
+        }
+        let summary = assembler::assemble(&target.params)?;
+        diagnostics::summary(&target.params.input_path, &target.params.output_path, &summary, None);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod project_tests {
+    use super::*;
+
+    const TEST_MANIFEST: &str = "tests/project/vilmos.toml";
+
+    #[test]
+    fn targets() {
+        let targets = load(TEST_MANIFEST, &[("max_width", "7".to_string())]).unwrap();
+        let names: Vec<&str> = targets.iter().map(|target| target.name.as_str()).collect();
+        assert_eq!(vec!["hello", "count"], names);
+        let hello = &targets[0].params;
+        assert_eq!(Path::new("tests/project/src/hello.vasm"), Path::new(&hello.input_path));
+        assert_eq!(vec![Path::new("tests/project/lib")], hello.include_dirs.iter().map(Path::new).collect::<Vec<&Path>>());
+        assert_eq!("grayscale", hello.palette);
+        assert_eq!(2, hello.pixel_size);
+        assert_eq!(7, hello.max_width);
+        assert_eq!(Path::new("tests/project/../palette.toml"), Path::new(&targets[1].params.palette));
+        assert!(targets[1].params.structured);
+    }
+
+    #[test]
+    fn included_files() {
+        let targets = load(TEST_MANIFEST, &[]).unwrap();
+        let files = assembler::dependencies(&targets[0].params).unwrap();
+        assert_eq!(vec![Path::new("tests/project/src/hello.vasm"), Path::new("tests/project/lib/newline.vasm")],
+                   files.iter().map(PathBuf::as_path).collect::<Vec<&Path>>());
+        assert!(is_stale(&targets[0], TEST_MANIFEST).unwrap());
+    }
+
+    #[test]
+    fn invalid_manifest() {
+        assert!(matches!(load("tests/project/missing.toml", &[]), Err(Error::Io(_))));
+        assert!(matches!(load("tests/conf.ini", &[]), Err(Error::Config(_))));
+    }
+}
@@ -0,0 +1,115 @@
+//! Size statistics of an assembled program, printed with `--stats` to track size regressions.
+
+use std::collections::BTreeMap;
+
+use crate::color::Color;
+use crate::config::json_string;
+use crate::instructions;
+use crate::instructions::Instruction;
+use crate::params::Params;
+
+/// Instructions of a kind and the pixels they take
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct KindStats {
+    pub instructions: usize,
+    pub pixels: usize,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stats {
+    pub kinds: BTreeMap<&'static str, KindStats>,
+    /// Pixels of the instructions without parameters, one opcode each
+    pub literal_pixels: usize,
+    /// Pixels generated by the instructions with parameters, like `RAW_INT` and `RAW_STRING`
+    pub expanded_pixels: usize,
+    /// Values that couldn't get an exact color and were pushed as `EASY_NUMBER + val - EASY_NUMBER`
+    pub fallbacks: usize,
+}
+
+impl Stats {
+    /// Appends the pixels of `instruction` to `colors` and records them
+    pub fn push(&mut self, conf: &Params, instruction: Instruction, colors: &mut Vec<Color>) -> Result<(), String> {
+        let fallbacks = instructions::fallbacks();
+        let name = instruction.name();
+        let literal = instruction.is_opcode();
+        let mut pixels = conf.get_color(instruction)?;
+        self.fallbacks += instructions::fallbacks() - fallbacks;
+        let kind = self.kinds.entry(name).or_default();
+        kind.instructions += 1;
+        kind.pixels += pixels.len();
+        match literal {
+            true => self.literal_pixels += pixels.len(),
+            false => self.expanded_pixels += pixels.len()
+        }
+        colors.append(&mut pixels);
+        Ok(())
+    }
+
+    /// Kinds sorted from the one with the most pixels
+    fn sorted_kinds(&self) -> Vec<(&'static str, KindStats)> {
+        let mut kinds: Vec<(&'static str, KindStats)> = self.kinds.iter().map(|(name, kind)| (*name, *kind)).collect();
+        kinds.sort_by(|a, b| b.1.pixels.cmp(&a.1.pixels).then(a.0.cmp(b.0)));
+        kinds
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = String::from("Pixels per instruction:\n");
+        for (name, kind) in self.sorted_kinds() {
+            text.push_str(&format!("  {:<18} {:>8} pixels {:>8} instructions\n", name, kind.pixels, kind.instructions));
+        }
+        text.push_str(&format!("Literal opcode pixels: {}\n", self.literal_pixels));
+        text.push_str(&format!("Expanded pixels: {}\n", self.expanded_pixels));
+        text.push_str(&format!("Exact color fallbacks: {}\n", self.fallbacks));
+        text
+    }
+
+    pub fn to_json(&self) -> String {
+        let kinds: Vec<String> = self.sorted_kinds().iter()
+            .map(|(name, kind)| format!("{}:{{\"instructions\":{},\"pixels\":{}}}", json_string(name), kind.instructions, kind.pixels))
+            .collect();
+        format!("{{\"kinds\":{{{}}},\"literal_pixels\":{},\"expanded_pixels\":{},\"fallbacks\":{}}}",
+                kinds.join(","), self.literal_pixels, self.expanded_pixels, self.fallbacks)
+    }
+}
+
+#[cfg(test)]
+mod stats_tests {
+    use super::*;
+
+    #[test]
+    fn pixels() {
+        let params = Params::test();
+        let mut stats = Stats::default();
+        let mut colors: Vec<Color> = Vec::new();
+        for instruction in [Instruction::RawInt(5), Instruction::RawInt(100000), Instruction::OutputInt, Instruction::Dup] {
+            stats.push(&params, instruction, &mut colors).unwrap();
+        }
+        assert_eq!(colors.len(), stats.literal_pixels + stats.expanded_pixels);
+        assert_eq!(2, stats.literal_pixels);
+        assert_eq!(KindStats { instructions: 2, pixels: stats.expanded_pixels }, stats.kinds["RAW_INT"]);
+        assert_eq!(KindStats { instructions: 1, pixels: 1 }, stats.kinds["DUP"]);
+        assert!(stats.to_json().starts_with("{\"kinds\":{\"RAW_INT\":{\"instructions\":2,"));
+    }
+
+    #[test]
+    fn fallbacks() {
+        let mut params = Params::test();
+        let mut stats = Stats::default();
+        // Black is the only color of 0
+        let mut colors: Vec<Color> = Vec::new();
+        stats.push(&params, Instruction::RawInt(0), &mut colors).unwrap();
+        assert_eq!((vec![Color::new(0, 0, 0)], 0), (colors, stats.fallbacks));
+        params.custom_colors.insert(Instruction::Dup, Color::new(0, 0, 0));
+        stats.push(&params, Instruction::RawInt(0), &mut Vec::new()).unwrap();
+        assert_eq!(1, stats.fallbacks);
+    }
+
+    #[test]
+    fn measured_colors() {
+        let params = Params { compress_strings: true, delta_strings: true, ..Params::test() };
+        let mut stats = Stats::default();
+        stats.push(&params, Instruction::RawStringBare("abab".to_string()), &mut Vec::new()).unwrap();
+        stats.push(&params, Instruction::RawStringBare("x".repeat(40)), &mut Vec::new()).unwrap();
+        assert_eq!(0, stats.fallbacks);
+    }
+}
@@ -0,0 +1,116 @@
+//! Assembly of very large programs in bounded memory, with the `streaming` setting.
+
+use crate::assembler;
+use crate::assembler::Summary;
+use crate::color;
+use crate::color::Color;
+use crate::error::Error;
+use crate::image::{ImageWriter, IndexedColors};
+use crate::instructions::Instruction;
+use crate::params::Params;
+use crate::stats::Stats;
+
+/// Calls `on_instruction` with every instruction of the input, `structured` is the compiled structured program
+fn for_each(conf: &Params, structured: &Option<Vec<Instruction>>,
+            on_instruction: &mut dyn FnMut(Instruction) -> Result<(), Error>) -> Result<(), Error> {
+    match structured {
+        Some(instructions) => instructions.iter().try_for_each(|instruction| on_instruction(instruction.clone())),
+        None => assembler::read_instructions(conf, &mut |_, instruction| on_instruction(instruction))
+    }
+}
+
+/// Reads the input twice: the first pass counts the pixels and collects the palette, the second one
+/// generates the same colors from the same seed and writes them row by row
+pub fn assemble(conf: &Params) -> Result<Summary, Error> {
+    if conf.output_path.to_lowercase().ends_with(".svg") || !conf.html.is_empty() || conf.preview {
+        return Err(Error::Usage("The streaming assembly writes only PNG images, without --html and --preview".to_string()));
+    }
+    let structured = match conf.structured {
+        true => Some(assembler::compile_structured(conf)?),
+        false => None
+    };
+    let seed = conf.seed.unwrap_or_else(rand::random);
+    color::seed(seed);
+    let mut pixels = 0;
+    let mut palette = conf.indexed.then(IndexedColors::default);
+    for_each(conf, &structured, &mut |instruction| {
+        let colors = conf.get_color(instruction).map_err(|err| Error::parse("invalid-argument", err))?;
+        for color in colors {
+            pixels += 1;
+            if palette.as_mut().is_some_and(|palette| !palette.insert(color)) {
+                palette = None;
+            }
+        }
+        Ok(())
+    })?;
+    let palette = palette.and_then(|palette| palette.padded(conf, pixels));
+    let mut writer = ImageWriter::create(conf, pixels, palette)?;
+    color::seed(seed);
+    let mut stats = conf.stats.then(Stats::default);
+    let mut colors: Vec<Color> = Vec::new();
+    for_each(conf, &structured, &mut |instruction| {
+        match &mut stats {
+            Some(stats) => stats.push(conf, instruction, &mut colors),
+            None => conf.get_color(instruction).map(|mut pixels| colors.append(&mut pixels))
+        }
+        .map_err(|err| Error::parse("invalid-argument", err))?;
+        colors.drain(..).try_for_each(|color| writer.push(color))
+    })?;
+    let png = writer.finish()?;
+    Ok(Summary { stats, png: Some(png), ..Summary::new(conf, pixels) })
+}
+
+#[cfg(test)]
+mod stream_tests {
+    use std::env;
+    use std::fs;
+
+    use crate::metadata;
+
+    use super::*;
+
+    fn output(name: &str) -> String {
+        env::temp_dir().join(format!("vilmos_{}_{}.png", name, std::process::id())).to_string_lossy().to_string()
+    }
+
+    fn pixels(path: &str, pixel_size: u16) -> Vec<Color> {
+        assembler::read_image(&Params { input_path: path.to_string(), pixel_size, ..Default::default() }).unwrap()
+    }
+
+    /// Assembles the input with and without streaming, checking that the images have the same pixels
+    fn compare(mut params: Params) {
+        params.seed = Some(7);
+        params.read_config().unwrap();
+        params.output_path = output("regular");
+        let regular = assembler::assemble(&params).unwrap();
+        params.output_path = output("streaming");
+        params.streaming = true;
+        let streaming = assembler::assemble(&params).unwrap();
+        assert_eq!(Summary { png: None, ..regular.clone() }, Summary { png: None, ..streaming.clone() });
+        let (regular_png, streaming_png) = (regular.png.unwrap(), streaming.png.unwrap());
+        assert_eq!((regular_png.palette, regular_png.bit_depth), (streaming_png.palette, streaming_png.bit_depth));
+        assert_eq!(pixels(&output("regular"), params.pixel_size), pixels(&output("streaming"), params.pixel_size));
+        let chunks = metadata::read(&output("streaming")).unwrap();
+        assert_eq!("7", chunks["Seed"]);
+        assert!(chunks.contains_key(metadata::SOURCE));
+        assert_eq!(metadata::read(&output("regular")).unwrap(), chunks);
+        fs::remove_file(output("regular")).unwrap();
+        fs::remove_file(output("streaming")).unwrap();
+    }
+
+    #[test]
+    fn same_pixels() {
+        compare(Params { input_path: "tests/test1.vasm".to_string(), max_width: 4, stats: true, ..Default::default() });
+        let mut params = Params { input_path: "tests/project/src/hello.vasm".to_string(), pixel_size: 3, ..Default::default() };
+        params.include_dirs.push("tests/project/lib".to_string());
+        compare(params);
+        compare(Params { input_path: "tests/project/src/count.vilmos".to_string(), structured: true, indexed: false, ..Default::default() });
+        compare(Params { input_path: "tests/empty.vasm".to_string(), ..Default::default() });
+    }
+
+    #[test]
+    fn png_only() {
+        let params = Params { input_path: "tests/test1.vasm".to_string(), output_path: "out.svg".to_string(), ..Default::default() };
+        assert!(matches!(assemble(&params), Err(Error::Usage(_))));
+    }
+}
@@ -0,0 +1,131 @@
+//! SVG rendering of the assembled image, chosen when the output ends with `.svg`.
+
+use std::fmt::Write;
+use std::fs;
+
+use crate::assembler;
+use crate::assembler::Summary;
+use crate::color::Color;
+use crate::disassembler;
+use crate::error::Error;
+use crate::instructions::Instruction;
+use crate::palette;
+use crate::params::Params;
+
+const LEGEND_ROW: u32 = 20;
+const LEGEND_FONT: u32 = 14;
+const LEGEND_WIDTH: u32 = 200;
+
+/// Escapes the characters that can't appear in the text of an element
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+fn text_color(color: &Color) -> &'static str {
+    if color.is_dark() { "#fff" } else { "#000" }
+}
+
+pub fn render(conf: &Params, colors: &[Color]) -> String {
+    let (columns, rows) = assembler::layout(conf, colors.len());
+    let cell = conf.pixel_size.max(1) as u32;
+    let (width, height) = (columns * cell, rows * cell);
+    let legend: Vec<Instruction> = match conf.legend {
+        true => palette::opcodes().collect(),
+        false => Vec::new()
+    };
+    let total_width = if legend.is_empty() { width } else { width.max(LEGEND_WIDTH) };
+    let total_height = height + LEGEND_ROW * legend.len() as u32;
+    let table = conf.color_table();
+    let quit = conf.opcode_color(Instruction::Quit);
+    let mut svg = String::new();
+    writeln!(svg, "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{0}\" height=\"{1}\" viewBox=\"0 0 {0} {1}\">",
+             total_width, total_height).unwrap();
+    svg.push_str("<g shape-rendering=\"crispEdges\">\n");
+    for i in 0..(columns * rows) as usize {
+        let color = colors.get(i).unwrap_or(&quit);
+        let (x, y) = ((i as u32 % columns) * cell, (i as u32 / columns) * cell);
+        writeln!(svg, "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"#{}\"/>", x, y, cell, cell, color.hex()).unwrap();
+    }
+    svg.push_str("</g>\n");
+    if conf.labels {
+        let font = cell as f32 / 5.0;
+        writeln!(svg, "<g font-family=\"monospace\" font-size=\"{}\" text-anchor=\"middle\" dominant-baseline=\"central\">", font).unwrap();
+        for i in 0..(columns * rows) as usize {
+            let color = colors.get(i).unwrap_or(&quit);
+            let (x, y) = ((i as u32 % columns) * cell, (i as u32 / columns) * cell);
+            let label = disassembler::label(&table, color);
+            // Monospace glyphs are about 0.6 times the font size, longer labels are squeezed into the pixel
+            let fit = match label.len() as f32 * font * 0.6 > cell as f32 * 0.9 {
+                true => format!(" textLength=\"{}\" lengthAdjust=\"spacingAndGlyphs\"", cell as f32 * 0.9),
+                false => String::new()
+            };
+            writeln!(svg, "<text x=\"{}\" y=\"{}\" fill=\"{}\"{}>{}</text>", x as f32 + cell as f32 / 2.0,
+                     y as f32 + cell as f32 / 2.0, text_color(color), fit, escape(&label)).unwrap();
+        }
+        svg.push_str("</g>\n");
+    }
+    if conf.grid {
+        let mut path = String::new();
+        for column in 0..=columns {
+            write!(path, "M{} 0V{}", column * cell, height).unwrap();
+        }
+        for row in 0..=rows {
+            write!(path, "M0 {}H{}", row * cell, width).unwrap();
+        }
+        writeln!(svg, "<path d=\"{}\" stroke=\"#808080\" stroke-width=\"{}\" fill=\"none\"/>", path, cell as f32 / 20.0).unwrap();
+    }
+    if !legend.is_empty() {
+        writeln!(svg, "<g font-family=\"monospace\" font-size=\"{}\" dominant-baseline=\"central\">", LEGEND_FONT).unwrap();
+        for (i, instruction) in legend.iter().enumerate() {
+            let y = height + LEGEND_ROW * i as u32;
+            let color = conf.opcode_color(instruction.clone());
+            writeln!(svg, "<rect x=\"2\" y=\"{}\" width=\"{2}\" height=\"{2}\" fill=\"#{}\" stroke=\"#808080\"/>",
+                     y + 2, color.hex(), LEGEND_ROW - 4).unwrap();
+            writeln!(svg, "<text x=\"{}\" y=\"{}\">{}</text>", LEGEND_ROW + 4, y + LEGEND_ROW / 2, instruction.name()).unwrap();
+        }
+        svg.push_str("</g>\n");
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+pub fn write_svg(conf: &Params, colors: &[Color]) -> Result<Summary, Error> {
+    fs::write(&conf.output_path, render(conf, colors)).map_err(|err| Error::io(&conf.output_path, err))?;
+    Ok(Summary::new(conf, colors.len()))
+}
+
+#[cfg(test)]
+mod svg_tests {
+    use super::*;
+
+    fn params() -> Params {
+        Params { pixel_size: 10, max_width: 2, ..Params::test() }
+    }
+
+    #[test]
+    fn cells() {
+        let params = params();
+        let colors = assembler::instructions_to_colors(&params, vec![Instruction::RawInt(300), Instruction::Dup, Instruction::Sum]).unwrap();
+        let svg = render(&params, &colors);
+        assert!(svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"20\" height=\"20\""));
+        assert_eq!(4, svg.matches("<rect").count());
+        assert!(svg.contains(&format!("<rect x=\"10\" y=\"10\" width=\"10\" height=\"10\" fill=\"#{}\"/>",
+                                      params.opcode_color(Instruction::Quit).hex())));
+        assert!(!svg.contains("<text") && !svg.contains("<path"));
+    }
+
+    #[test]
+    fn decorations() {
+        let mut params = params();
+        params.labels = true;
+        params.grid = true;
+        params.legend = true;
+        let colors = assembler::instructions_to_colors(&params, vec![Instruction::RawInt(300), Instruction::OutputInt]).unwrap();
+        let svg = render(&params, &colors);
+        assert!(svg.contains("\">300</text>"));
+        assert!(svg.contains("lengthAdjust=\"spacingAndGlyphs\">OUTPUT_INT</text>"));
+        assert!(svg.contains("<path d=\"M0 0V10M10 0V10M20 0V10M0 0H20M0 10H20\""));
+        assert_eq!(2 + palette::opcodes().count(), svg.matches("<rect").count());
+        assert!(svg.contains(">WHILE_END</text>"));
+    }
+}
@@ -0,0 +1,40 @@
+//! Watch mode: the input is assembled again every time it, or one of the files it reads, changes.
+
+use std::fs;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::assembler;
+use crate::diagnostics;
+use crate::params::Params;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+fn snapshot(files: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    files.iter().map(|file| fs::metadata(file).and_then(|metadata| metadata.modified()).ok()).collect()
+}
+
+pub fn watch(conf: &Params) -> ! {
+    let mut files = vec![PathBuf::from(&conf.input_path)];
+    let mut last: Vec<Option<SystemTime>> = Vec::new();
+    diagnostics::note(format!("Watching {}", conf.input_path));
+    loop {
+        if snapshot(&files) != last {
+            let result = assembler::dependencies(conf).and_then(|dependencies| {
+                files = dependencies;
+                last = snapshot(&files);
+                assembler::assemble(conf)
+            });
+            match result {
+                Ok(summary) => diagnostics::summary(&conf.input_path, &conf.output_path, &summary, Some(format!("Wrote {}", conf.output_path))),
+                Err(err) => {
+                    // The files of the last successful read are still watched, so fixing the error triggers a new build
+                    last = snapshot(&files);
+                    diagnostics::error(&err);
+                }
+            }
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}